@@ -0,0 +1,78 @@
+//! Measures what `render_on_blocking` costs and saves, for a small and a large template.
+//!
+//! Run with `cargo run --release --example render_latency`. Three numbers are printed per
+//! setting: the mean latency of a small and of a large page served alone, and the latency of
+//! a small page requested while eight large pages are rendering on the same worker.
+
+use std::time::{Duration, Instant};
+
+use actix_tera_page::TeraPage;
+use actix_web::{
+    test::{self, TestRequest},
+    web::Data,
+    App,
+};
+use futures_util::future::join_all;
+use tera::{Context, Tera};
+
+const RUNS: u32 = 200;
+
+#[actix_web::main]
+async fn main() {
+    let mut tera = Tera::default();
+    tera.add_raw_templates([
+        ("pages/small.html", "<p>{{ greeting }}</p>"),
+        (
+            "pages/large.html",
+            "<ul>{% for i in range(end=20000) %}<li>{{ i }} {{ greeting }}</li>{% endfor %}</ul>",
+        ),
+    ])
+    .unwrap();
+
+    println!(
+        "{:<10} {:>12} {:>12} {:>16}",
+        "", "small", "large", "small under load"
+    );
+    for blocking in [false, true] {
+        let page = TeraPage::new("pages", |_| async {
+            let mut context = Context::new();
+            context.insert("greeting", "hello");
+            context
+        })
+        .render_on_blocking(blocking);
+        let app = test::init_service(App::new().app_data(Data::new(tera.clone())).wrap(page)).await;
+        // The time from `started` until the page for `uri` is served.
+        let get = |uri: &'static str, started: Instant| {
+            let app = &app;
+            async move {
+                let res = test::call_service(app, TestRequest::get().uri(uri).to_request()).await;
+                assert!(res.status().is_success());
+                started.elapsed()
+            }
+        };
+
+        let mut small = Duration::ZERO;
+        let mut large = Duration::ZERO;
+        for _ in 0..RUNS {
+            small += get("/small", Instant::now()).await;
+            large += get("/large", Instant::now()).await;
+        }
+
+        // The small page arrives just after the large ones, as all of them are in flight.
+        let mut loaded = Duration::ZERO;
+        for _ in 0..RUNS / 10 {
+            let started = Instant::now();
+            let busy = join_all((0..8).map(|_| get("/large", started)));
+            let (_, latency) = futures_util::join!(busy, get("/small", started));
+            loaded += latency;
+        }
+
+        println!(
+            "{:<10} {:>12.1?} {:>12.1?} {:>16.1?}",
+            if blocking { "blocking" } else { "inline" },
+            small / RUNS,
+            large / RUNS,
+            loaded / (RUNS / 10),
+        );
+    }
+}
@@ -0,0 +1,207 @@
+//! Render cache for pages whose output doesn't depend on the request.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, RwLockWriteGuard,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::ServiceRequest,
+    http::{
+        header::{self, ContentRange, ContentRangeSpec, Header, Range},
+        StatusCode,
+    },
+    web::Bytes,
+    HttpResponse, HttpResponseBuilder,
+};
+use tera::Tera;
+
+/// Counts the template sets a middleware instance has served, so that pages rendered with
+/// an earlier one are dropped from its caches.
+#[derive(Clone, Default)]
+pub(crate) struct Generation {
+    current: Arc<AtomicU64>,
+    /// The `Tera` seen last, held on to so its address can't be reused by another one.
+    last: Arc<Mutex<Option<Arc<Tera>>>>,
+}
+
+impl Generation {
+    pub(crate) fn get(&self) -> u64 {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// Start a new generation if `tera` isn't the `Tera` seen last.
+    pub(crate) fn observe(&self, tera: &Arc<Tera>) {
+        let mut last = self.last.lock().unwrap();
+        if last.as_ref().is_some_and(|last| !Arc::ptr_eq(last, tera)) {
+            self.current.fetch_add(1, Ordering::Relaxed);
+        }
+        *last = Some(tera.clone());
+    }
+}
+
+/// Rendered bodies of static pages, keyed by template, path and the request inputs of the page.
+#[derive(Clone, Default)]
+pub(crate) struct RenderCache {
+    pages: Arc<RwLock<Pages>>,
+}
+
+#[derive(Default)]
+struct Pages {
+    generation: u64,
+    bodies: HashMap<String, Page>,
+    /// Bodies rendered ahead of traffic that still need a request to run the post-render hook.
+    warmed: HashMap<String, String>,
+}
+
+struct Page {
+    body: Bytes,
+    rendered_at: Instant,
+    /// The body compressed with each encoding it has been sent with, by encoding name.
+    #[cfg(any(feature = "brotli", feature = "gzip"))]
+    encoded: HashMap<String, Bytes>,
+}
+
+#[cfg(any(feature = "brotli", feature = "gzip"))]
+impl Page {
+    /// Returns true if `body` is this page's body. Cached bodies are handed out as clones
+    /// sharing their allocation, so comparing pointers is enough.
+    fn holds(&self, body: &Bytes) -> bool {
+        self.body.as_ptr() == body.as_ptr() && self.body.len() == body.len()
+    }
+}
+
+impl RenderCache {
+    pub(crate) fn get(&self, key: &str, generation: u64) -> Option<Bytes> {
+        self.get_aged(key, generation).map(|(body, _)| body)
+    }
+
+    /// A body cached for the template `generation`, along with how long ago it was rendered.
+    pub(crate) fn get_aged(&self, key: &str, generation: u64) -> Option<(Bytes, Duration)> {
+        let pages = self.pages.read().unwrap();
+        if pages.generation != generation {
+            return None;
+        }
+        let page = pages.bodies.get(key)?;
+        Some((page.body.clone(), page.rendered_at.elapsed()))
+    }
+
+    /// `body` compressed with `encoding`, if it is still the cached body of `key` and was
+    /// compressed with it before.
+    #[cfg(any(feature = "brotli", feature = "gzip"))]
+    pub(crate) fn get_encoded(
+        &self,
+        key: &str,
+        body: &Bytes,
+        encoding: &str,
+        generation: u64,
+    ) -> Option<Bytes> {
+        let pages = self.pages.read().unwrap();
+        if pages.generation != generation {
+            return None;
+        }
+        let page = pages.bodies.get(key).filter(|page| page.holds(body))?;
+        page.encoded.get(encoding).cloned()
+    }
+
+    /// Keep `encoded`, which is `body` compressed with `encoding`, for as long as `body` is
+    /// the cached body of `key`.
+    #[cfg(any(feature = "brotli", feature = "gzip"))]
+    pub(crate) fn insert_encoded(
+        &self,
+        key: &str,
+        body: &Bytes,
+        encoding: String,
+        encoded: Bytes,
+        generation: u64,
+    ) {
+        let mut pages = self.pages.write().unwrap();
+        if pages.generation != generation {
+            return;
+        }
+        if let Some(page) = pages.bodies.get_mut(key).filter(|page| page.holds(body)) {
+            page.encoded.insert(encoding, encoded);
+        }
+    }
+
+    /// Store a body rendered with the templates of `generation`, unless they have been
+    /// replaced since.
+    pub(crate) fn insert(&self, key: String, body: Bytes, generation: u64) {
+        if let Some(mut pages) = self.current(generation) {
+            let page = Page {
+                body,
+                rendered_at: Instant::now(),
+                #[cfg(any(feature = "brotli", feature = "gzip"))]
+                encoded: HashMap::new(),
+            };
+            pages.bodies.insert(key, page);
+        }
+    }
+
+    /// Hold a body rendered without a request until [`take_warmed`](Self::take_warmed).
+    pub(crate) fn insert_warmed(&self, key: String, body: String, generation: u64) {
+        if let Some(mut pages) = self.current(generation) {
+            pages.warmed.insert(key, body);
+        }
+    }
+
+    pub(crate) fn take_warmed(&self, key: &str, generation: u64) -> Option<String> {
+        let mut pages = self.pages.write().unwrap();
+        if pages.generation != generation {
+            return None;
+        }
+        pages.warmed.remove(key)
+    }
+
+    /// The pages, emptied first if they were rendered with an earlier generation, or `None`
+    /// if `generation` is the earlier one.
+    fn current(&self, generation: u64) -> Option<RwLockWriteGuard<'_, Pages>> {
+        let mut pages = self.pages.write().unwrap();
+        if generation < pages.generation {
+            return None;
+        }
+        if generation > pages.generation {
+            pages.generation = generation;
+            pages.bodies.clear();
+            pages.warmed.clear();
+        }
+        Some(pages)
+    }
+}
+
+/// Finish the response for a static page, answering a single-range `Range` request with
+/// `206 Partial Content`. Multi-range and non-byte requests get the full body.
+pub(crate) fn ranged_response(
+    req: &ServiceRequest,
+    mut response: HttpResponseBuilder,
+    body: Bytes,
+) -> HttpResponse {
+    response.insert_header((header::ACCEPT_RANGES, "bytes"));
+
+    let length = body.len() as u64;
+    let spec = match Range::parse(req) {
+        Ok(Range::Bytes(specs)) if specs.len() == 1 => specs.into_iter().next().unwrap(),
+        _ => return response.body(body),
+    };
+
+    match spec.to_satisfiable_range(length) {
+        Some((start, end)) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(length),
+            }))
+            .body(body.slice(start as usize..=end as usize)),
+        None => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: None,
+                instance_length: Some(length),
+            }))
+            .finish(),
+    }
+}
@@ -0,0 +1,48 @@
+//! Compression of rendered pages, negotiated from the request's `Accept-Encoding`.
+
+use std::io::{self, Write};
+
+use actix_web::{
+    dev::ServiceRequest,
+    http::header::{AcceptEncoding, Encoding, Header},
+    web::Bytes,
+};
+
+/// The encodings compiled in, most preferred first when the client has no preference.
+const SUPPORTED: &[Encoding] = &[
+    #[cfg(feature = "brotli")]
+    Encoding::brotli(),
+    #[cfg(feature = "gzip")]
+    Encoding::gzip(),
+    Encoding::identity(),
+];
+
+/// The best encoding the client accepts, honoring `q`-values, or `None` to send the page
+/// unencoded.
+pub(crate) fn negotiate(req: &ServiceRequest) -> Option<Encoding> {
+    AcceptEncoding::parse(req)
+        .ok()?
+        .negotiate(SUPPORTED.iter())
+        .filter(|encoding| *encoding != Encoding::identity())
+}
+
+pub(crate) fn encode(encoding: &Encoding, body: &[u8]) -> io::Result<Bytes> {
+    #[cfg(feature = "brotli")]
+    if *encoding == Encoding::brotli() {
+        let mut encoded = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut encoded, 4096, 5, 22);
+            writer.write_all(body)?;
+        }
+        return Ok(encoded.into());
+    }
+
+    #[cfg(feature = "gzip")]
+    if *encoding == Encoding::gzip() {
+        let mut writer = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        writer.write_all(body)?;
+        return Ok(writer.finish()?.into());
+    }
+
+    Ok(Bytes::copy_from_slice(body))
+}
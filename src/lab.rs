@@ -0,0 +1,27 @@
+//! Interop with `actix-web-lab`, whose `Html` responder builds the responses of HTML pages.
+
+use actix_web::{
+    http::header, web::Bytes, HttpRequest, HttpResponse, HttpResponseBuilder, Responder,
+};
+use actix_web_lab::respond::Html;
+
+/// Respond with `body` through `Html`, keeping the status and headers set on `response`
+/// other than the content type, which `Html` sets itself.
+pub(crate) fn html_response(
+    req: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    body: Bytes,
+) -> HttpResponse {
+    let built = response.finish();
+    let mut page = Html::new(String::from_utf8_lossy(&body))
+        .respond_to(req)
+        .map_into_boxed_body();
+
+    *page.status_mut() = built.status();
+    for (name, value) in built.headers() {
+        if name != header::CONTENT_TYPE {
+            page.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    page
+}
@@ -1,5 +1,5 @@
 //! This crate provides a middleware for `actix_web` that reduces the boilerplate needed to
-//! create SSR websites with `Tera`. It matches GET request paths to templates and renders them
+//! create SSR websites with `Tera`. It matches GET and HEAD request paths to templates and renders them
 //! using a shared "base context". An example use case would be populating a website navbar
 //! with user information or login/signup buttons, depending on if there is a user logged in or not.
 //!
@@ -7,7 +7,10 @@
 //! Register a `Tera` object as app data on the web server, then wrap the application or route
 //! in the middleware constructed with `TeraPage::new`.
 //!
-//! ```
+//! ```no_run
+//! # use actix_tera_page::TeraPage;
+//! # use actix_web::{get, web::{self, Data}, App, HttpRequest, HttpServer, Responder};
+//! # use tera::{Context, Tera};
 //! struct State {
 //!     name: String,
 //! }
@@ -27,7 +30,7 @@
 //! async fn complex_page(tera: web::Data<Tera>, req: HttpRequest) -> impl Responder {
 //!     // The `base_context` function can be reused as a starting point
 //!     // for pages with more complex requirements.
-//!     let mut context = base_context(req.clone());
+//!     let mut context = base_context(req.clone()).await;
 //!     context.insert("more-info", "data");
 //!     tera.render("complex-page.html", &context).unwrap()
 //! }
@@ -60,141 +63,4163 @@
 //! ```
 //!
 //! A functional example can be found in the `examples` directory.
+//!
+//! Matching only looks at template names, so templates embedded in the binary (e.g. with
+//! `include_dir` or `rust-embed`) work like ones loaded from disk, as long as they are
+//! registered under the names a glob would give them: relative to the template root, with
+//! `/` separators and no leading slash, such as `pages/about.html` for `/about`.
+//!
+//! ```
+//! # use actix_tera_page::TeraPage;
+//! # use actix_web::HttpRequest;
+//! # use tera::{Context, Tera};
+//! # async fn base_context(_: HttpRequest) -> Context { Context::new() }
+//! let mut tera = Tera::default();
+//! tera.add_raw_templates(vec![
+//!     ("pages/index.html", include_str!("../examples/templates/pages/index.html")),
+//!     ("base.html", include_str!("../examples/templates/base.html")),
+//! ])
+//! .unwrap();
+//!
+//! let page = TeraPage::new("pages", base_context);
+//! ```
+
+#[cfg(feature = "static-cache")]
+mod cache;
+#[cfg(any(feature = "brotli", feature = "gzip"))]
+mod compress;
+#[cfg(feature = "csp")]
+mod csp;
+#[cfg(feature = "lab")]
+mod lab;
+mod metrics;
+mod minify;
+
+pub use arc_swap::ArcSwap;
+pub use metrics::TeraPageMetrics;
+#[cfg(feature = "regex")]
+pub use regex::Regex;
 
-use std::future::{ready, Future, Ready};
+use std::{
+    borrow::Cow,
+    collections::{
+        hash_map::{DefaultHasher, RandomState},
+        HashMap, HashSet,
+    },
+    env, fmt,
+    future::{ready, Future},
+    hash::{BuildHasher, Hash, Hasher},
+    net::IpAddr,
+    ops::Deref,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
+#[cfg(feature = "actix-session")]
+use actix_session::SessionExt;
 use actix_web::{
-    body::BoxBody,
+    body::{BoxBody, EitherBody, MessageBody},
+    cookie::{Cookie, SameSite},
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    http::Method,
-    web::Data,
-    Error, HttpRequest, HttpResponse,
+    http::{
+        header::{
+            self, Accept, AcceptLanguage, CacheControl, CacheDirective, ContentDisposition, ETag,
+            EntityTag, Header, HeaderName, HeaderValue, IfNoneMatch, Preference,
+        },
+        Method, StatusCode,
+    },
+    middleware::{ErrorHandlerResponse, ErrorHandlers},
+    mime, rt,
+    web::{self, Bytes, Data, ServiceConfig},
+    Error, HttpMessage, HttpRequest, HttpResponse,
+};
+use futures_util::{
+    future::{BoxFuture, LocalBoxFuture},
+    stream,
 };
-use futures_util::future::LocalBoxFuture;
-use log::debug;
-use tera::{Context, Tera};
+use log::{debug, error, warn};
+use serde::Serialize;
+use tera::{Context, Tera, Value};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
-/// Middleware constructor.
-pub struct TeraPage<C, F>
-where
-    C: Fn(HttpRequest) -> F,
-    F: Future<Output = Context>,
-{
-    context_builder: C,
+type ValueExtractor = Rc<dyn Fn(&ServiceRequest) -> Option<Value>>;
+type PrefixResolver = Rc<dyn Fn(&str) -> Option<String>>;
+type RequestPredicate = Rc<dyn Fn(&HttpRequest) -> bool>;
+
+type VariantResolver = Rc<dyn Fn(&HttpRequest) -> Option<String>>;
+type ThemeResolver = Rc<dyn Fn(&HttpRequest) -> Vec<String>>;
+type AttachmentResolver = Rc<dyn Fn(&str, &HttpRequest) -> Option<String>>;
+type FallthroughHook = Rc<dyn Fn(&str, &[String])>;
+type EtagResolver = Rc<dyn Fn(&str, &HttpRequest) -> Option<String>>;
+type PathRewrite = Rc<dyn for<'a> Fn(&'a str) -> Cow<'a, str>>;
+type CookieResolver = Rc<dyn Fn(&HttpRequest) -> Vec<Cookie<'static>>>;
+type PostRender = Rc<dyn Fn(String, &HttpRequest) -> LocalBoxFuture<'static, String>>;
+type CandidateStage = Rc<dyn Fn(Vec<String>) -> Vec<String>>;
+type ResponseOverride = Rc<dyn Fn(HttpRequest, String) -> HttpResponse>;
+type ContextGate = Rc<dyn Fn(&Context, &str) -> PageOutcome>;
+type PageResult = Result<ServiceResponse<BoxBody>, Error>;
+type FallThrough = Box<dyn FnOnce(ServiceRequest) -> LocalBoxFuture<'static, PageResult>>;
+
+/// The A/B variant whose template was matched, stored in the request extensions.
+struct AbVariant(String);
+
+/// The device class resolved for the request, stored in the request extensions.
+struct DeviceClass(String);
+
+/// The `HX-Trigger` value taken from the context of an htmx request.
+struct HxTrigger(String);
+
+/// The child pages of a directory being served by the autoindex listing template.
+struct Autoindex(Vec<String>);
+
+/// Values captured from the path by a [`MatchStrategy`] rule.
+struct Captures(tera::Map<String, Value>);
+
+/// The path templates were matched against, after rewriting and collapsing slashes.
+struct MatchedPath(String);
+
+#[derive(Clone)]
+struct Maintenance {
+    flag: Arc<AtomicBool>,
+    template: String,
+}
+
+#[derive(Clone)]
+struct LegalBlock {
+    predicate: RequestPredicate,
+    template: String,
+}
+
+/// Stale-while-revalidate settings for static pages.
+#[cfg(feature = "static-cache")]
+#[derive(Clone)]
+struct Swr {
+    max_age: Duration,
+    stale_window: Duration,
+    /// Cache keys of pages being re-rendered in the background by this worker.
+    revalidating: Rc<std::cell::RefCell<HashSet<String>>>,
+}
+
+/// A long-lived context, and how to rebuild it.
+#[derive(Clone)]
+struct SharedContext {
+    current: Arc<ArcSwap<Context>>,
+    refresh: ContextRefresh,
+}
+
+type ContextRefresh = Rc<dyn Fn() -> LocalBoxFuture<'static, Context>>;
+
+/// A `Tera` instance resolved for a request, however it was registered.
+type TeraHandle = Arc<dyn Deref<Target = Tera> + Send + Sync>;
+type TeraLookup = Rc<dyn Fn(&ServiceRequest) -> Option<TeraHandle>>;
+
+/// App data of a type wrapping a `Tera`, viewed as the `Tera` itself.
+struct WrappedTera<T>(Data<T>);
+
+impl<T: AsRef<Tera>> Deref for WrappedTera<T> {
+    type Target = Tera;
+
+    fn deref(&self) -> &Tera {
+        T::as_ref(&self.0)
+    }
+}
+
+/// Options shared by `TeraPage` and the middleware it constructs.
+#[derive(Clone)]
+struct Config {
     template_prefix: String,
+    extension: String,
+    index_name: String,
+    fragment_suffix: Option<String>,
+    fragment_headers: Vec<HeaderName>,
+    locales: Vec<String>,
+    max_body_size: Option<usize>,
+    shared_context: Option<SharedContext>,
+    globals: Context,
+    noindex: Vec<String>,
+    principal: Option<ValueExtractor>,
+    redact_template_paths: bool,
+    content_types: Vec<(String, String)>,
+    cache_control: HashMap<String, String>,
+    preload: HashMap<String, Vec<String>>,
+    enabled: Option<Arc<AtomicBool>>,
+    compose: Vec<String>,
+    template_redirects: bool,
+    force_autoescape: bool,
+    escaping: Rc<std::cell::RefCell<EscapingTera>>,
+    metrics: TeraPageMetrics,
+    ambiguity: Ambiguity,
+    inject_template_source: bool,
+    tera_lookup: TeraLookup,
+    subdomain_prefix: Option<PrefixResolver>,
+    success_status: StatusCode,
+    legal_block: Option<LegalBlock>,
+    skip_prefixes: Vec<String>,
+    render_limit: Option<Arc<Semaphore>>,
+    fail_fast: bool,
+    ab_variant: Option<VariantResolver>,
+    template_header: Option<HeaderName>,
+    honor_no_cache: Option<RequestPredicate>,
+    autoindex: Option<String>,
+    pipeline: Vec<CandidateStage>,
+    request_id: Option<HeaderName>,
+    ignore_patterns: Vec<String>,
+    attachment: Option<AttachmentResolver>,
+    maintenance: Option<Maintenance>,
+    maintenance_allowlist: Vec<String>,
+    match_strategy: MatchStrategy,
+    on_fallthrough: Option<FallthroughHook>,
+    dev_glob: Option<String>,
+    etag: Option<EtagResolver>,
+    minify: bool,
+    section_teras: Vec<(String, Arc<Tera>)>,
+    index_canonical: Option<TrailingSlash>,
+    seo_files: bool,
+    rewrite_path: Option<PathRewrite>,
+    not_found_body: Option<(String, Bytes)>,
+    build_info: Option<BuildInfo>,
+    template_index: Option<Arc<HashSet<String>>>,
+    set_cookies: Option<CookieResolver>,
+    post_render: Option<PostRender>,
+    keys: InjectionKeys,
+    aliases: HashMap<String, String>,
+    auto_private: bool,
+    skip_extensions: Vec<String>,
+    handle_options: bool,
+    device_variant: Option<VariantResolver>,
+    prefix_path: PrefixPath,
+    csrf_token: Option<ValueExtractor>,
+    static_errors: HashMap<StatusCode, &'static str>,
+    context_gate: Option<ContextGate>,
+    theme_chain: Option<ThemeResolver>,
+    preserve_query: bool,
+    max_depth: Option<usize>,
+    host_namespacing: bool,
+    logger: Option<Arc<dyn TeraPageLogger>>,
+    overrides: HashMap<String, ResponseOverride>,
+    htmx: Option<HtmxConfig>,
+    collapse_slashes: bool,
+    redirect_slashes: bool,
+    render_timeout: Option<Duration>,
+    render_on_blocking: bool,
+    #[cfg(feature = "csp")]
+    csp_hashes: bool,
+    nojs: Option<(String, String)>,
+    inject_path: bool,
+    #[cfg(feature = "lab")]
+    lab_html: bool,
+    #[cfg(feature = "actix-session")]
+    flash: bool,
+    #[cfg(feature = "static-cache")]
+    static_pages: Vec<String>,
+    #[cfg(feature = "static-cache")]
+    cache: cache::RenderCache,
+    #[cfg(feature = "static-cache")]
+    generation: cache::Generation,
+    #[cfg(feature = "static-cache")]
+    stale: Option<cache::RenderCache>,
+    #[cfg(feature = "static-cache")]
+    swr: Option<Swr>,
+    #[cfg(feature = "static-cache")]
+    warm: Option<(TeraHandle, Context, Vec<String>)>,
 }
 
-impl<C, F> TeraPage<C, F>
-where
-    C: Fn(HttpRequest) -> F,
-    F: Future<Output = Context>,
-{
-    /// Create a new instance with a given template search prefix and a function that builds the context.
-    pub fn new(template_prefix: &str, context_builder: C) -> Self {
-        TeraPage {
-            context_builder,
-            template_prefix: template_prefix.to_string(),
+impl Config {
+    fn new(template_prefix: &str) -> Self {
+        Config {
+            template_prefix: template_prefix.trim_matches('/').to_string(),
+            extension: "html".to_string(),
+            index_name: "index".to_string(),
+            fragment_suffix: None,
+            fragment_headers: vec![HX_REQUEST, HeaderName::from_static("x-requested-with")],
+            locales: Vec::new(),
+            max_body_size: None,
+            shared_context: None,
+            globals: Context::new(),
+            noindex: Vec::new(),
+            principal: None,
+            redact_template_paths: false,
+            content_types: vec![
+                ("json".to_string(), "application/json".to_string()),
+                ("csv".to_string(), "text/csv; charset=utf-8".to_string()),
+                ("sse".to_string(), "text/event-stream".to_string()),
+            ],
+            cache_control: HashMap::new(),
+            preload: HashMap::new(),
+            enabled: None,
+            compose: Vec::new(),
+            template_redirects: false,
+            force_autoescape: false,
+            escaping: Default::default(),
+            metrics: TeraPageMetrics::new(),
+            ambiguity: Ambiguity::default(),
+            inject_template_source: false,
+            tera_lookup: Rc::new(|req| {
+                req.app_data::<Data<Tera>>()
+                    .map(|tera| Arc::new(tera.clone().into_inner()) as TeraHandle)
+            }),
+            subdomain_prefix: None,
+            success_status: StatusCode::OK,
+            legal_block: None,
+            skip_prefixes: vec!["/.well-known".to_string()],
+            render_limit: None,
+            fail_fast: false,
+            ab_variant: None,
+            template_header: None,
+            honor_no_cache: None,
+            autoindex: None,
+            pipeline: Vec::new(),
+            request_id: None,
+            ignore_patterns: Vec::new(),
+            attachment: None,
+            maintenance: None,
+            maintenance_allowlist: Vec::new(),
+            match_strategy: MatchStrategy::Exact,
+            on_fallthrough: None,
+            dev_glob: None,
+            etag: None,
+            minify: false,
+            section_teras: Vec::new(),
+            index_canonical: None,
+            seo_files: false,
+            rewrite_path: None,
+            not_found_body: None,
+            build_info: None,
+            template_index: None,
+            set_cookies: None,
+            post_render: None,
+            keys: InjectionKeys::default(),
+            aliases: HashMap::new(),
+            auto_private: false,
+            skip_extensions: [
+                "png", "jpg", "jpeg", "gif", "webp", "avif", "svg", "ico", "css", "js", "mjs",
+                "map", "woff", "woff2", "ttf", "otf",
+            ]
+            .iter()
+            .map(|e| e.to_string())
+            .collect(),
+            handle_options: false,
+            device_variant: None,
+            prefix_path: PrefixPath::default(),
+            csrf_token: None,
+            static_errors: HashMap::new(),
+            context_gate: None,
+            theme_chain: None,
+            preserve_query: true,
+            max_depth: None,
+            host_namespacing: false,
+            logger: None,
+            overrides: HashMap::new(),
+            htmx: None,
+            collapse_slashes: true,
+            redirect_slashes: false,
+            render_timeout: None,
+            render_on_blocking: false,
+            #[cfg(feature = "csp")]
+            csp_hashes: false,
+            nojs: None,
+            inject_path: false,
+            #[cfg(feature = "lab")]
+            lab_html: false,
+            #[cfg(feature = "actix-session")]
+            flash: false,
+            #[cfg(feature = "static-cache")]
+            static_pages: Vec::new(),
+            #[cfg(feature = "static-cache")]
+            cache: cache::RenderCache::default(),
+            #[cfg(feature = "static-cache")]
+            generation: cache::Generation::default(),
+            #[cfg(feature = "static-cache")]
+            stale: None,
+            #[cfg(feature = "static-cache")]
+            swr: None,
+            #[cfg(feature = "static-cache")]
+            warm: None,
+        }
+    }
+
+    /// Build the templates to try for a path, in order of preference. Each group holds
+    /// the variants of one way of naming the page (e.g. `about.html` vs `about/index.html`).
+    /// Template variants (e.g. `about.fragment.html`) are tried before the base template of
+    /// each group, in the order given.
+    fn candidates(&self, prefix: &str, path: &str, variants: &[String]) -> Vec<Vec<String>> {
+        let Config {
+            extension,
+            index_name: index,
+            ..
+        } = self;
+        let path = path.trim_end_matches('/');
+
+        let stems = if path.is_empty() {
+            vec![format!("{}/{}", prefix, index)]
+        } else if self.ambiguity == Ambiguity::FirstWins {
+            vec![
+                format!("{}{}", prefix, path),
+                format!("{}{}/{}", prefix, path, index),
+            ]
+        } else {
+            vec![
+                format!("{}{}/{}", prefix, path, index),
+                format!("{}{}", prefix, path),
+            ]
+        };
+
+        let mut groups = Vec::new();
+
+        // Paths with a registered content extension (e.g. `/config.json`) name their template.
+        if let Some(extension) = extension_of(path) {
+            let seo_file = self.seo_files
+                && SEO_FILES
+                    .iter()
+                    .any(|(file, _)| path == format!("/{}", file));
+            if seo_file || self.content_types.iter().any(|(e, _)| e == extension) {
+                groups.push(vec![format!("{}{}", prefix, path)]);
+            }
+        }
+
+        for stem in stems {
+            let mut group = variants
+                .iter()
+                .map(|variant| format!("{}.{}.{}", stem, variant, extension))
+                .collect::<Vec<_>>();
+            group.push(format!("{}.{}", stem, extension));
+            groups.push(group);
+        }
+
+        groups
+            .into_iter()
+            .map(|group| {
+                self.pipeline
+                    .iter()
+                    .fold(group, |group, stage| stage(group))
+            })
+            .map(|group| {
+                group
+                    .into_iter()
+                    .filter(|c| !self.is_ignored(prefix, c))
+                    .collect::<Vec<_>>()
+            })
+            .filter(|group| !group.is_empty())
+            .collect()
+    }
+
+    /// Returns true if a segment of `template` below `prefix` matches an ignore pattern.
+    fn is_ignored(&self, prefix: &str, template: &str) -> bool {
+        let relative = template
+            .strip_prefix(prefix)
+            .map_or(template, |rest| rest.trim_start_matches('/'));
+        relative.split('/').any(|segment| {
+            self.ignore_patterns
+                .iter()
+                .any(|pattern| wildcard_match(pattern, segment))
+        })
+    }
+
+    /// The content type to serve a template with, based on its extension.
+    fn content_type(&self, template: &str) -> Option<&str> {
+        let extension = extension_of(template)?;
+        if extension == self.extension {
+            return Some("text/html; charset=utf-8");
         }
+
+        let registered = self
+            .content_types
+            .iter()
+            .find(|(e, _)| e == extension)
+            .map(|(_, mime)| mime.as_str());
+        registered.or_else(|| {
+            let name = template.rsplit('/').next()?;
+            SEO_FILES
+                .iter()
+                .find(|(file, _)| self.seo_files && *file == name)
+                .map(|(_, mime)| *mime)
+        })
+    }
+
+    /// A `status` response carrying its [`TeraPage::static_error_page`], if one is set.
+    fn static_error(&self, status: StatusCode) -> Option<HttpResponse> {
+        let body = self.static_errors.get(&status)?;
+        Some(
+            HttpResponse::build(status)
+                .content_type("text/html; charset=utf-8")
+                .body(*body),
+        )
+    }
+
+    /// The generation of the templates being served, which changes when a reloadable or
+    /// active `Tera` is replaced.
+    fn generation(&self) -> u64 {
+        #[cfg(feature = "static-cache")]
+        return self.generation.get();
+        #[cfg(not(feature = "static-cache"))]
+        0
+    }
+
+    /// `tera`, or a copy of it that also autoescapes the page extension if
+    /// [`TeraPage::force_autoescape`] is set and it doesn't already. The copy is kept for
+    /// as long as the same `Tera` is served.
+    fn autoescaping(&self, tera: TeraHandle) -> TeraHandle {
+        if !self.force_autoescape {
+            return tera;
+        }
+
+        let mut escaping = self.escaping.borrow_mut();
+        // Tera only takes static suffixes, so the page extension's is leaked, once.
+        let suffix = match escaping.suffix {
+            Some(suffix) if suffix[1..] == self.extension => suffix,
+            _ => {
+                let suffix: &'static str =
+                    Box::leak(format!(".{}", self.extension).into_boxed_str());
+                escaping.suffix = Some(suffix);
+                suffix
+            }
+        };
+        if tera.autoescape_suffixes.contains(&suffix) {
+            return tera;
+        }
+        if let Some((original, copy)) = &escaping.copy {
+            // The original is held on to, so its address can't be reused by another `Tera`.
+            if std::ptr::eq::<Tera>(&***original, &**tera) {
+                return copy.clone();
+            }
+        }
+
+        debug!("Copying Tera to autoescape {:?} templates.", suffix);
+        let mut copy = Tera::clone(&tera);
+        let mut suffixes = copy.autoescape_suffixes.clone();
+        suffixes.push(suffix);
+        copy.autoescape_on(suffixes);
+        let copy = Arc::new(Box::new(copy)) as TeraHandle;
+        escaping.copy = Some((tera, copy.clone()));
+        copy
+    }
+
+    /// The options rendering depends on, owned so renders can move to another thread.
+    fn render_options(&self) -> RenderOptions {
+        RenderOptions {
+            minify: self.minify,
+            extension: self.extension.clone(),
+            compose: self.compose.clone(),
+            #[cfg(feature = "test-util")]
+            metrics: self.metrics.clone(),
+        }
+    }
+
+    /// The name of a template as it should appear in logs and error responses.
+    fn display_name<'a>(&self, template: &'a str) -> Cow<'a, str> {
+        if !self.redact_template_paths {
+            return Cow::Borrowed(template);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        template.hash(&mut hasher);
+        Cow::Owned(format!("template-{:016x}", hasher.finish()))
     }
 }
 
-impl<S, C, F> Transform<S, ServiceRequest> for TeraPage<C, F>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
-    S::Future: 'static,
-    C: Fn(HttpRequest) -> F + Copy,
-    F: Future<Output = Context> + 'static,
-{
-    type Response = ServiceResponse<BoxBody>;
-    type Error = Error;
-    type InitError = ();
-    type Transform = TeraPageMiddleware<S, C, F>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+/// The autoescaping copy of the served `Tera` made for [`TeraPage::force_autoescape`].
+#[derive(Default)]
+struct EscapingTera {
+    suffix: Option<&'static str>,
+    /// The `Tera` last served, and its copy.
+    copy: Option<(TeraHandle, TeraHandle)>,
+}
 
-    fn new_transform(&self, service: S) -> Self::Future {
-        ready(Ok(TeraPageMiddleware {
-            service,
-            context_builder: self.context_builder,
-            template_prefix: self.template_prefix.trim_matches('/').to_string(),
-        }))
+/// The parts of a [`Config`] that [`render`] uses.
+struct RenderOptions {
+    minify: bool,
+    extension: String,
+    compose: Vec<String>,
+    #[cfg(feature = "test-util")]
+    metrics: TeraPageMetrics,
+}
+
+/// Files served at the site root by [`TeraPage::seo_files`], with their content types.
+const SEO_FILES: &[(&str, &str)] = &[
+    ("robots.txt", "text/plain; charset=utf-8"),
+    ("sitemap.xml", "application/xml"),
+];
+
+const HX_REQUEST: HeaderName = HeaderName::from_static("hx-request");
+
+/// Methods pages are served for.
+const PAGE_METHODS: &[Method] = &[Method::GET, Method::HEAD];
+
+/// How to resolve a path matching both a template named after it and an index template,
+/// such as `pages/foo.html` and `pages/foo/index.html` for `/foo`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Ambiguity {
+    /// Serve the template named after the path.
+    FirstWins,
+    /// Serve the index template.
+    #[default]
+    LastWins,
+    /// Respond with a 500 listing the conflicting templates.
+    Error,
+}
+
+/// How request paths are mapped to templates.
+#[derive(Clone, Debug, Default)]
+pub enum MatchStrategy {
+    /// Look for templates named after the path.
+    #[default]
+    Exact,
+    /// Serve the template paired with the first pattern matching the whole path, where `*`
+    /// matches any run of characters, e.g. `("/docs/*", "pages/docs.html")`.
+    Glob(Vec<(String, String)>),
+    /// Serve the template named by expanding the format string paired with the first
+    /// matching expression, e.g. `^/blog/(\d+)$` to `pages/blog_post.html`. `$1` or
+    /// `$name` in the format string refer to captures, which are also inserted into the
+    /// context as `captures`.
+    #[cfg(feature = "regex")]
+    Regex(Vec<(Regex, String)>),
+}
+
+impl MatchStrategy {
+    /// The template for `path` and the captures that selected it, if a rule matches.
+    fn route(&self, path: &str) -> Option<(String, tera::Map<String, Value>)> {
+        match self {
+            MatchStrategy::Exact => None,
+            MatchStrategy::Glob(rules) => rules
+                .iter()
+                .find(|(pattern, _)| wildcard_match(pattern, path))
+                .map(|(_, template)| (template.clone(), tera::Map::new())),
+            #[cfg(feature = "regex")]
+            MatchStrategy::Regex(rules) => rules.iter().find_map(|(regex, format)| {
+                let captures = regex.captures(path)?;
+                let mut template = String::new();
+                captures.expand(format, &mut template);
+
+                let mut values = tera::Map::new();
+                for (i, name) in regex.capture_names().enumerate() {
+                    if let Some(capture) = captures.get(i) {
+                        let key = name.map_or_else(|| i.to_string(), str::to_string);
+                        values.insert(key, capture.as_str().into());
+                    }
+                }
+                Some((template, values))
+            }),
+        }
     }
 }
 
-pub struct TeraPageMiddleware<S, C, F>
-where
-    C: Fn(HttpRequest) -> F,
-    F: Future<Output = Context>,
-{
-    service: S,
-    context_builder: C,
-    template_prefix: String,
+/// How a request for the URL form of the template prefix itself, such as `/pages` for the
+/// prefix `pages`, is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PrefixPath {
+    /// Match it like any other path, to `pages/pages.html`.
+    #[default]
+    Page,
+    /// Pass it to the inner service.
+    FallThrough,
+    /// Answer with `404 Not Found`.
+    NotFound,
+    /// Serve the index page, as for `/`.
+    Index,
 }
 
-impl<S, C, F> Service<ServiceRequest> for TeraPageMiddleware<S, C, F>
-where
-    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>, Error = Error>,
-    S::Future: 'static,
-    C: Fn(HttpRequest) -> F,
-    F: Future<Output = Context> + 'static,
-{
-    type Response = ServiceResponse<BoxBody>;
-    type Error = Error;
-    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+/// Whether the canonical path of a page backed by an index template, such as
+/// `pages/blog/index.html` for `/blog`, ends with a slash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// `/blog/`.
+    #[default]
+    Always,
+    /// `/blog`.
+    Trim,
+}
 
-    forward_ready!(service);
+/// Builds the context for a request, as an alternative to a context builder closure for
+/// implementations that carry their own state:
+///
+/// ```
+/// # use actix_tera_page::ContextProvider;
+/// # use actix_web::HttpRequest;
+/// # use futures_util::future::LocalBoxFuture;
+/// # use tera::Context;
+/// struct Navigation {
+///     links: Vec<String>,
+/// }
+///
+/// impl ContextProvider for Navigation {
+///     fn build<'a>(&'a self, _req: &'a HttpRequest) -> LocalBoxFuture<'a, Context> {
+///         Box::pin(async move {
+///             let mut context = Context::new();
+///             context.insert("links", &self.links);
+///             context
+///         })
+///     }
+/// }
+/// ```
+pub trait ContextProvider {
+    fn build<'a>(&'a self, req: &'a HttpRequest) -> LocalBoxFuture<'a, Context>;
+}
 
-    fn call(&self, req: ServiceRequest) -> Self::Future {
-        if req.method() != Method::GET {
-            let req = self.service.call(req);
-            return Box::pin(async move {
-                let r = req.await?;
-                Ok(r)
-            });
+/// Receives the events the middleware would otherwise log through the `log` crate, set
+/// with [`TeraPage::logger`]. Template names are redacted as for log output. Every method
+/// does nothing by default.
+pub trait TeraPageLogger {
+    /// The templates about to be checked for a request path.
+    fn candidates(&self, _path: &str, _candidates: &[String]) {}
+    /// A request path matched `template`.
+    fn matched(&self, _path: &str, _template: &str) {}
+    /// A request path matched no template and is passed to the inner service.
+    fn fallthrough(&self, _path: &str) {}
+    /// Rendering `template` failed with `message`.
+    fn render_error(&self, _template: &str, _message: &str) {}
+}
+
+/// Conversion of request data, such as an authenticated principal, into a context value.
+pub trait IntoContextValue {
+    fn context_value(&self) -> Value;
+}
+
+/// Error returned when a `TeraPage` is configured with invalid values.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// An environment variable is set to a value that can't be used.
+    InvalidEnv { var: &'static str, value: String },
+    /// A cache config file couldn't be read or parsed.
+    CacheConfig { path: String, message: String },
+    /// A template prefix has no pages under it.
+    EmptyPrefix { prefix: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidEnv { var, value } => {
+                write!(f, "invalid value for {}: {:?}", var, value)
+            }
+            ConfigError::CacheConfig { path, message } => {
+                write!(f, "invalid cache config {}: {}", path, message)
+            }
+            ConfigError::EmptyPrefix { prefix } => {
+                write!(f, "no page templates under prefix {:?}", prefix)
+            }
         }
+    }
+}
 
-        let tera = if let Some(tera) = req.app_data::<Data<Tera>>() {
-            tera.clone()
-        } else {
-            panic!("A Tera object must be registered as application data for TeraPageMiddlewear to work!");
+impl std::error::Error for ConfigError {}
+
+type ContextBuilder = Rc<dyn Fn(HttpRequest) -> LocalBoxFuture<'static, tera::Result<PageOutcome>>>;
+
+/// What a builder set with [`TeraPageBuilder::outcome_builder`] decided for a request.
+#[derive(Clone, Debug)]
+pub enum PageOutcome {
+    /// Render the page with this context.
+    Render(Context),
+    /// Answer with `503 Service Unavailable` and a `Retry-After` header instead, e.g. when a
+    /// dependency the page needs is overloaded.
+    Unavailable { retry_after: Duration },
+    /// Answer with this status and an empty body instead, e.g. `404 Not Found`.
+    Status(StatusCode),
+    /// Pass the request on to the inner service, as if no template had matched.
+    FallThrough,
+}
+
+impl From<Context> for PageOutcome {
+    fn from(context: Context) -> Self {
+        PageOutcome::Render(context)
+    }
+}
+
+/// The ID of a request, as used by [`TeraPage::request_id`]. Middleware running before
+/// `TeraPage` can insert it into the request extensions to supply their own.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Build metadata merged into every context under `build` by [`TeraPage::build_info`].
+/// The [`build_info!`] macro fills it in at compile time.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    pub git_sha: Option<String>,
+    pub built_at: Option<String>,
+}
+
+/// Build a [`BuildInfo`] for the calling crate: its `CARGO_PKG_VERSION`, plus the
+/// `GIT_SHA` and `BUILD_TIMESTAMP` environment variables if they were set at compile time
+/// (e.g. by a build script).
+///
+/// ```
+/// # use actix_tera_page::{build_info, TeraPage};
+/// # use actix_web::HttpRequest;
+/// # use tera::Context;
+/// # async fn base_context(_: HttpRequest) -> Context { Context::new() }
+/// let page = TeraPage::new("pages", base_context).build_info(build_info!());
+/// ```
+#[macro_export]
+macro_rules! build_info {
+    () => {
+        $crate::BuildInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            git_sha: option_env!("GIT_SHA").map(str::to_string),
+            built_at: option_env!("BUILD_TIMESTAMP").map(str::to_string),
+        }
+    };
+}
+
+/// The context keys the middleware inserts request-derived values under, set with
+/// [`TeraPage::injection_keys`]. Renaming them avoids collisions with values of the
+/// context builder's own; the defaults are the field names. A value is only inserted if
+/// the context builder hasn't already set its key, so the builder can always override it.
+#[derive(Clone, Debug)]
+pub struct InjectionKeys {
+    pub locale: String,
+    pub user: String,
+    pub csrf_token: String,
+    pub ab_variant: String,
+    pub device: String,
+    pub canonical: String,
+    pub build: String,
+    pub request_id: String,
+    pub captures: String,
+    pub entries: String,
+    pub template_source: String,
+    pub path: String,
+    pub normalized_path: String,
+    #[cfg(feature = "actix-session")]
+    pub flash: String,
+}
+
+impl Default for InjectionKeys {
+    fn default() -> Self {
+        InjectionKeys {
+            locale: "locale".to_string(),
+            user: "user".to_string(),
+            csrf_token: "csrf_token".to_string(),
+            ab_variant: "ab_variant".to_string(),
+            device: "device".to_string(),
+            canonical: "canonical".to_string(),
+            build: "build".to_string(),
+            request_id: "request_id".to_string(),
+            captures: "captures".to_string(),
+            entries: "entries".to_string(),
+            template_source: "template_source".to_string(),
+            path: "path".to_string(),
+            normalized_path: "normalized_path".to_string(),
+            #[cfg(feature = "actix-session")]
+            flash: "flash".to_string(),
+        }
+    }
+}
+
+/// How htmx requests are answered, set with [`TeraPage::htmx`].
+#[derive(Clone, Debug)]
+pub struct HtmxConfig {
+    /// Suffix of the fragment templates served to htmx requests, as for
+    /// [`TeraPage::fragment_suffix`]. Defaults to `fragment`.
+    pub fragment_suffix: String,
+    /// Send the request's path and query as `HX-Push-Url`, so htmx updates the address bar.
+    /// Enabled by default.
+    pub push_url: bool,
+    /// Context key whose value, when the context has it, is sent as `HX-Trigger` to fire
+    /// client-side events. Strings are sent as they are and other values as JSON.
+    pub trigger_key: Option<String>,
+}
+
+impl Default for HtmxConfig {
+    fn default() -> Self {
+        HtmxConfig {
+            fragment_suffix: "fragment".to_string(),
+            push_url: true,
+            trigger_key: None,
+        }
+    }
+}
+
+/// The effective configuration of a [`TeraPage`], as returned by [`TeraPage::describe`]
+/// for diagnostics.
+#[derive(Clone, Debug)]
+pub struct TeraPageConfigSummary {
+    pub prefix: String,
+    pub extension: String,
+    pub index_name: String,
+    /// Extensions served with their own content types, besides `extension`.
+    pub content_types: Vec<(String, String)>,
+    pub skip_prefixes: Vec<String>,
+    pub skip_extensions: Vec<String>,
+    pub max_body_size: Option<usize>,
+    pub max_depth: Option<usize>,
+    pub render_timeout: Option<Duration>,
+    /// Paths of static pages, which are cached after their first render.
+    pub static_pages: Vec<String>,
+    /// Stale-while-revalidate freshness and stale window of the static page cache.
+    pub swr: Option<(Duration, Duration)>,
+    /// Names of the options that are switched on, after the methods setting them.
+    pub options: Vec<&'static str>,
+    /// Cargo features the crate was compiled with.
+    pub features: Vec<&'static str>,
+}
+
+/// A stage of [`TeraPage::candidate_pipeline`].
+pub type CandidateTransform = Box<dyn Fn(Vec<String>) -> Vec<String>>;
+
+/// Middleware constructor.
+pub struct TeraPage {
+    context_builder: ContextBuilder,
+    config: Config,
+}
+
+impl TeraPage {
+    /// Placeholder marking where the page body goes in a [`TeraPage::compose`] list.
+    pub const COMPOSED_PAGE: &'static str = "{page}";
+
+    /// Context key marking a page as user-specific for [`TeraPage::auto_private`].
+    pub const PRIVATE_KEY: &'static str = "__private";
+
+    /// Session key [`TeraPage::flash`] reads flash messages from.
+    #[cfg(feature = "actix-session")]
+    pub const FLASH_KEY: &'static str = "flash";
+
+    /// Create a new instance with a given template search prefix and a function that builds the context.
+    ///
+    /// Like the rest of actix-web's middleware, an instance is created per worker and runs
+    /// on that worker's thread, so the builder's future doesn't need to be `Send`; `Send`
+    /// futures, such as ones shared with multithreaded code, are accepted all the same. Use
+    /// [`TeraPageSend`] to create the builder outside the worker.
+    pub fn new<C, F>(template_prefix: &str, context_builder: C) -> Self
+    where
+        C: Fn(HttpRequest) -> F + 'static,
+        F: Future<Output = Context> + 'static,
+    {
+        TeraPage::builder()
+            .prefix(template_prefix)
+            .context_builder(context_builder)
+            .build()
+    }
+
+    /// Create a new instance whose builder returns any serializable value instead of a
+    /// `Context`. See [`TeraPageBuilder::serialize_builder`].
+    pub fn new_serialize<C, F, T>(template_prefix: &str, context_builder: C) -> Self
+    where
+        C: Fn(HttpRequest) -> F + 'static,
+        F: Future<Output = T> + 'static,
+        T: Serialize,
+    {
+        TeraPage::builder()
+            .prefix(template_prefix)
+            .serialize_builder(context_builder)
+            .build()
+    }
+
+    /// Start configuring an instance with a [`TeraPageBuilder`], which sets what the
+    /// constructors take, for the combinations they don't cover.
+    pub fn builder() -> TeraPageBuilder {
+        TeraPageBuilder::default()
+    }
+
+    /// Create a new instance for pages that need no dynamic data, rendering each with an
+    /// empty context (plus the values the middleware adds itself).
+    pub fn static_only(template_prefix: &str) -> Self {
+        TeraPage::builder().prefix(template_prefix).build()
+    }
+
+    /// Create a new instance that builds contexts with a [`ContextProvider`] instead of a
+    /// closure.
+    pub fn with_provider(template_prefix: &str, provider: Arc<dyn ContextProvider>) -> Self {
+        TeraPage::new(template_prefix, move |req| {
+            let provider = provider.clone();
+            async move { provider.build(&req).await }
+        })
+    }
+
+    /// Create a new instance configured from the `TERA_PAGE_PREFIX`, `TERA_PAGE_EXTENSION`
+    /// and `TERA_PAGE_INDEX` environment variables, defaulting to `pages`, `html` and `index`.
+    pub fn from_env<C, F>(context_builder: C) -> Result<Self, ConfigError>
+    where
+        C: Fn(HttpRequest) -> F + 'static,
+        F: Future<Output = Context> + 'static,
+    {
+        let read = |var: &'static str, default: &str| match env::var(var) {
+            Ok(value) => Ok(value),
+            Err(env::VarError::NotPresent) => Ok(default.to_string()),
+            Err(env::VarError::NotUnicode(value)) => Err(ConfigError::InvalidEnv {
+                var,
+                value: value.to_string_lossy().into_owned(),
+            }),
         };
 
-        let path = req.path().trim_end_matches('/');
+        let prefix = read("TERA_PAGE_PREFIX", "pages")?;
+        if prefix.split('/').any(|segment| segment == "..") {
+            return Err(ConfigError::InvalidEnv {
+                var: "TERA_PAGE_PREFIX",
+                value: prefix,
+            });
+        }
 
-        let candidates = if !path.is_empty() {
-            vec![
-                format!("{}{}.html", self.template_prefix, path),
-                format!("{}{}/index.html", self.template_prefix, path),
-            ]
-        } else {
-            vec![format!("{}/index.html", self.template_prefix)]
+        let segment = |var: &'static str, default: &str| {
+            let value = read(var, default)?;
+            let value = value.trim_start_matches('.');
+            if value.is_empty() || value.contains(['/', '.']) {
+                return Err(ConfigError::InvalidEnv {
+                    var,
+                    value: value.to_string(),
+                });
+            }
+            Ok(value.to_string())
         };
 
-        debug!("Checking template candidates: {:?}", candidates);
+        let extension = segment("TERA_PAGE_EXTENSION", "html")?;
+        let index_name = segment("TERA_PAGE_INDEX", "index")?;
 
-        let templates = tera.get_template_names().collect::<Vec<&str>>();
-        let mut matched_template = None;
-        for c in candidates {
-            if templates.contains(&c.as_str()) {
-                matched_template = Some(c);
+        Ok(TeraPage::builder()
+            .prefix(&prefix)
+            .extension(&extension)
+            .index_name(&index_name)
+            .context_builder(context_builder)
+            .build())
+    }
+
+    /// Pick the template prefix for the deployment environment named by the `TERA_PAGE_ENV`
+    /// environment variable, e.g. `{"staging": "pages-staging", "production": "pages"}`.
+    /// The current prefix is kept when the variable is unset. Fails if the variable names an
+    /// environment missing from `prefixes`, or if `tera` has no pages under the new prefix.
+    pub fn prefix_for_env(
+        mut self,
+        tera: &Tera,
+        prefixes: HashMap<String, String>,
+    ) -> Result<Self, ConfigError> {
+        let environment = match env::var("TERA_PAGE_ENV") {
+            Ok(environment) => environment,
+            Err(env::VarError::NotPresent) => return Ok(self),
+            Err(env::VarError::NotUnicode(value)) => {
+                return Err(ConfigError::InvalidEnv {
+                    var: "TERA_PAGE_ENV",
+                    value: value.to_string_lossy().into_owned(),
+                })
+            }
+        };
+        let prefix = match prefixes.get(&environment) {
+            Some(prefix) => prefix.trim_matches('/').to_string(),
+            None => {
+                return Err(ConfigError::InvalidEnv {
+                    var: "TERA_PAGE_ENV",
+                    value: environment,
+                })
             }
+        };
+
+        let suffix = format!(".{}", self.config.extension);
+        let has_pages = tera.get_template_names().any(|name| {
+            let relative = match prefix.as_str() {
+                "" => Some(name),
+                prefix => name.strip_prefix(prefix).and_then(|n| n.strip_prefix('/')),
+            };
+            relative.is_some_and(|r| r.ends_with(&suffix)) && !self.config.is_ignored(&prefix, name)
+        });
+        if !has_pages {
+            return Err(ConfigError::EmptyPrefix { prefix });
         }
 
-        if let Some(template) = matched_template {
-            debug!("Matched path to template: {:?}", template);
-            let context = (self.context_builder)(req.request().clone());
+        debug!(
+            "Using prefix {:?} for environment {:?}.",
+            prefix, environment
+        );
+        self.config.template_prefix = prefix;
+        Ok(self)
+    }
 
-            Box::pin(async move {
-                Ok(req.into_response(
-                    HttpResponse::Ok().body(tera.render(&template, &context.await).unwrap()),
-                ))
-            })
-        } else {
-            debug!("No matching template for path.");
-            let req = self.service.call(req);
-            Box::pin(async move {
-                let r = req.await?;
-                Ok(r)
-            })
+    /// Summarize the effective configuration, e.g. to log it at startup.
+    pub fn describe(&self) -> TeraPageConfigSummary {
+        let config = &self.config;
+        let options = [
+            ("fragment_suffix", config.fragment_suffix.is_some()),
+            ("locales", !config.locales.is_empty()),
+            ("shared_context", config.shared_context.is_some()),
+            ("noindex", !config.noindex.is_empty()),
+            ("principal", config.principal.is_some()),
+            ("redact_template_paths", config.redact_template_paths),
+            ("cache_config", !config.cache_control.is_empty()),
+            ("preload_map", !config.preload.is_empty()),
+            ("enabled", config.enabled.is_some()),
+            ("compose", !config.compose.is_empty()),
+            ("allow_template_redirects", config.template_redirects),
+            ("force_autoescape", config.force_autoescape),
+            ("inject_template_source", config.inject_template_source),
+            ("subdomain_prefix", config.subdomain_prefix.is_some()),
+            ("legal_block", config.legal_block.is_some()),
+            ("max_concurrent_renders", config.render_limit.is_some()),
+            ("fail_fast", config.fail_fast),
+            ("ab_variant", config.ab_variant.is_some()),
+            ("template_header", config.template_header.is_some()),
+            ("honor_request_no_cache", config.honor_no_cache.is_some()),
+            ("autoindex", config.autoindex.is_some()),
+            ("candidate_pipeline", !config.pipeline.is_empty()),
+            ("request_id", config.request_id.is_some()),
+            ("ignore_pattern", !config.ignore_patterns.is_empty()),
+            ("attachment", config.attachment.is_some()),
+            ("maintenance", config.maintenance.is_some()),
+            ("on_fallthrough", config.on_fallthrough.is_some()),
+            ("dev_glob", config.dev_glob.is_some()),
+            ("etag_fn", config.etag.is_some()),
+            ("minify", config.minify),
+            ("tera_for_prefix", !config.section_teras.is_empty()),
+            ("index_canonical", config.index_canonical.is_some()),
+            ("seo_files", config.seo_files),
+            ("rewrite_path", config.rewrite_path.is_some()),
+            ("not_found_body", config.not_found_body.is_some()),
+            ("build_info", config.build_info.is_some()),
+            ("template_index", config.template_index.is_some()),
+            ("set_cookies", config.set_cookies.is_some()),
+            ("post_render_async", config.post_render.is_some()),
+            ("aliases", !config.aliases.is_empty()),
+            ("auto_private", config.auto_private),
+            ("handle_options", config.handle_options),
+            ("device_variant", config.device_variant.is_some()),
+            ("inject_csrf", config.csrf_token.is_some()),
+            ("static_error_page", !config.static_errors.is_empty()),
+            ("context_gate", config.context_gate.is_some()),
+            ("theme_chain", config.theme_chain.is_some()),
+            ("preserve_query_on_redirect", config.preserve_query),
+            ("host_namespacing", config.host_namespacing),
+            ("logger", config.logger.is_some()),
+            ("override_handler", !config.overrides.is_empty()),
+            ("htmx", config.htmx.is_some()),
+            ("collapse_slashes", config.collapse_slashes),
+            ("redirect_collapsed_slashes", config.redirect_slashes),
+            ("render_on_blocking", config.render_on_blocking),
+            #[cfg(feature = "csp")]
+            ("csp_hashes", config.csp_hashes),
+            ("nojs_variant", config.nojs.is_some()),
+            ("inject_path", config.inject_path),
+            #[cfg(feature = "lab")]
+            ("lab_html", config.lab_html),
+            #[cfg(feature = "actix-session")]
+            ("flash", config.flash),
+            #[cfg(feature = "static-cache")]
+            ("serve_stale_on_error", config.stale.is_some()),
+        ];
+        let features = [
+            #[cfg(feature = "actix-session")]
+            "actix-session",
+            #[cfg(feature = "brotli")]
+            "brotli",
+            #[cfg(feature = "cache-config")]
+            "cache-config",
+            #[cfg(feature = "csp")]
+            "csp",
+            #[cfg(feature = "gzip")]
+            "gzip",
+            #[cfg(feature = "lab")]
+            "lab",
+            #[cfg(feature = "regex")]
+            "regex",
+            #[cfg(feature = "static-cache")]
+            "static-cache",
+            #[cfg(feature = "test-util")]
+            "test-util",
+        ];
+
+        TeraPageConfigSummary {
+            prefix: config.template_prefix.clone(),
+            extension: config.extension.clone(),
+            index_name: config.index_name.clone(),
+            content_types: config.content_types.clone(),
+            skip_prefixes: config.skip_prefixes.clone(),
+            skip_extensions: config.skip_extensions.clone(),
+            max_body_size: config.max_body_size,
+            max_depth: config.max_depth,
+            render_timeout: config.render_timeout,
+            #[cfg(feature = "static-cache")]
+            static_pages: config.static_pages.clone(),
+            #[cfg(not(feature = "static-cache"))]
+            static_pages: Vec::new(),
+            #[cfg(feature = "static-cache")]
+            swr: config
+                .swr
+                .as_ref()
+                .map(|swr| (swr.max_age, swr.stale_window)),
+            #[cfg(not(feature = "static-cache"))]
+            swr: None,
+            options: options
+                .into_iter()
+                .filter_map(|(name, enabled)| enabled.then_some(name))
+                .collect(),
+            features: features.to_vec(),
+        }
+    }
+
+    /// Register a catch-all default service answering `404 Not Found`, so that unmatched
+    /// GET requests inside a scope still reach the middleware instead of being short-circuited
+    /// by routing. Intended to be used alongside `wrap`:
+    ///
+    /// ```no_run
+    /// # use actix_tera_page::TeraPage;
+    /// # use actix_web::{web, App, HttpRequest};
+    /// # use tera::Context;
+    /// # async fn base_context(_: HttpRequest) -> Context { Context::new() }
+    /// App::new().service(
+    ///     web::scope("")
+    ///         .configure(TeraPage::configure)
+    ///         .wrap(TeraPage::new("pages", base_context)),
+    /// );
+    /// ```
+    pub fn configure(cfg: &mut ServiceConfig) {
+        cfg.default_service(web::to(|| async { HttpResponse::NotFound().finish() }));
+    }
+
+    /// Build an [`ErrorHandlers`] middleware that replaces the body of error responses with
+    /// the `{status}` page under the prefix (e.g. `pages/404.html`), rendered with this
+    /// instance's context builder and options. Statuses without a page are left untouched,
+    /// as are responses to clients preferring something other than HTML, such as JSON API
+    /// clients, which get the [`not_found_body`](Self::not_found_body) for empty 404s instead.
+    ///
+    /// Wrap it outside the `TeraPage` middleware (i.e. register it later) so it also sees
+    /// the error responses produced by the middleware itself:
+    ///
+    /// ```no_run
+    /// # use actix_tera_page::TeraPage;
+    /// # use actix_web::{App, HttpRequest};
+    /// # use tera::Context;
+    /// # async fn base_context(_: HttpRequest) -> Context { Context::new() }
+    /// let page = TeraPage::new("pages", base_context);
+    /// let error_pages = page.with_error_handlers();
+    /// App::new().wrap(page).wrap(error_pages);
+    /// ```
+    pub fn with_error_handlers<B: MessageBody + 'static>(&self) -> ErrorHandlers<B> {
+        let config = Rc::new(self.config.clone());
+        let context_builder = self.context_builder.clone();
+
+        ErrorHandlers::new().default_handler(move |res: ServiceResponse<B>| {
+            let config = config.clone();
+            let context_builder = context_builder.clone();
+            Ok(ErrorHandlerResponse::Future(Box::pin(render_error_page(
+                config,
+                context_builder,
+                res,
+            ))))
+        })
+    }
+
+    /// Render `template` for `req` through the same pipeline as matched pages (context
+    /// builder, globals, injected values, hooks and response headers), for handlers serving
+    /// a page whose template can't be derived from the path:
+    ///
+    /// ```no_run
+    /// # use actix_tera_page::TeraPage;
+    /// # use actix_web::{web::Data, HttpRequest, HttpResponse};
+    /// # use tera::{Context, Tera};
+    /// # async fn base_context(_: HttpRequest) -> Context { Context::new() }
+    /// async fn welcome(page: Data<TeraPage>, tera: Data<Tera>, req: HttpRequest) -> HttpResponse {
+    ///     page.render_template(tera.into_inner(), &req, "pages/welcome.html").await
+    /// }
+    /// ```
+    pub async fn render_template<T>(
+        &self,
+        tera: T,
+        req: &HttpRequest,
+        template: &str,
+    ) -> HttpResponse
+    where
+        T: Deref<Target = Tera> + Send + Sync + 'static,
+    {
+        let req = ServiceRequest::from_request(req.clone());
+        let response = render_page(
+            Rc::new(self.config.clone()),
+            self.context_builder.clone(),
+            self.config.autoescaping(Arc::new(tera)),
+            self.config.generation(),
+            template.to_string(),
+            req,
+            None,
+        )
+        .await;
+
+        match response {
+            Ok(response) => response.into_parts().1,
+            Err(e) => HttpResponse::from_error(e),
         }
     }
+
+    /// Answer failures the middleware responds to with `status` using the fixed HTML `body`,
+    /// which doesn't depend on Tera. A `500 Internal Server Error` page is used for render
+    /// failures, ambiguous templates, oversized pages, and requests made without a registered
+    /// `Tera`, which otherwise panic. A `503 Service Unavailable` page is used for renders
+    /// that time out and for requests rejected by the render limit under fail-fast.
+    pub fn static_error_page(mut self, status: StatusCode, body: &'static str) -> Self {
+        self.config.static_errors.insert(status, body);
+        self
+    }
+
+    /// Body sent for empty 404 responses to clients that don't accept HTML, e.g.
+    /// `not_found_body("application/json", r#"{"error":"not found"}"#)`. Only applies within
+    /// [`with_error_handlers`](Self::with_error_handlers).
+    pub fn not_found_body(mut self, content_type: &str, body: &str) -> Self {
+        self.config.not_found_body = Some((
+            content_type.to_string(),
+            Bytes::copy_from_slice(body.as_bytes()),
+        ));
+        self
+    }
+
+    /// Set the file extension of page templates (`html` by default).
+    pub fn extension(mut self, extension: &str) -> Self {
+        self.config.extension = extension.trim_start_matches('.').to_string();
+        self
+    }
+
+    /// Serve requests for paths ending in `.{extension}` from the template of the same name,
+    /// with the given content type. `json`, `csv` and `sse` are registered as
+    /// `application/json`, `text/csv` and `text/event-stream` by default. Event streams are
+    /// sent chunked and uncompressed, asking proxies not to buffer them.
+    pub fn content_type(mut self, extension: &str, mime: &str) -> Self {
+        let extension = extension.trim_start_matches('.').to_string();
+        self.config.content_types.retain(|(e, _)| *e != extension);
+        self.config
+            .content_types
+            .push((extension, mime.to_string()));
+        self
+    }
+
+    /// Set the template name served for directory paths (`index` by default).
+    pub fn index_name(mut self, index_name: &str) -> Self {
+        self.config.index_name = index_name.to_string();
+        self
+    }
+
+    /// Serve a fragment variant of the matched template (e.g. `pages/about.fragment.html`
+    /// for the suffix `"fragment"`) to XHR/fetch requests, falling back to the full page.
+    /// Pages are sent with `Vary` naming the headers that mark fragment requests.
+    pub fn fragment_suffix(mut self, suffix: &str) -> Self {
+        self.config.fragment_suffix = Some(suffix.trim_matches('.').to_string());
+        self
+    }
+
+    /// Answer htmx requests, marked by the `HX-Request` header, with fragment templates and
+    /// htmx response headers as set by `htmx`. This replaces the fragment suffix and
+    /// headers, and pages are sent with `Vary: HX-Request`.
+    pub fn htmx(mut self, htmx: HtmxConfig) -> Self {
+        self.config.fragment_suffix = Some(htmx.fragment_suffix.trim_matches('.').to_string());
+        self.config.fragment_headers = vec![HX_REQUEST];
+        self.config.htmx = Some(htmx);
+        self
+    }
+
+    /// Set the request header that marks a fragment request, replacing the defaults
+    /// (`HX-Request` and `X-Requested-With`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `name` is not a valid header name.
+    pub fn fragment_header(mut self, name: &str) -> Self {
+        self.config.fragment_headers = vec![HeaderName::try_from(name)
+            .unwrap_or_else(|_| panic!("Invalid fragment header name: {:?}", name))];
+        self
+    }
+
+    /// Resolve a locale for each request from its `Accept-Language` header and insert it
+    /// into the context under `"locale"`, for use by templates and locale-aware filters.
+    /// The first supported locale is used when nothing else matches. A `"locale"` set by
+    /// the context builder is left untouched. Pages are sent with `Vary: Accept-Language`.
+    pub fn locales(mut self, supported: &[&str]) -> Self {
+        self.config.locales = supported.iter().map(|l| l.to_string()).collect();
+        self
+    }
+
+    /// Respond with a 500 instead of the page when its rendered output exceeds `limit` bytes.
+    pub fn max_body_size(mut self, limit: usize) -> Self {
+        self.config.max_body_size = Some(limit);
+        self
+    }
+
+    /// Attach `X-Robots-Tag: noindex, nofollow` to pages rendered for the given paths
+    /// and any path beneath them.
+    pub fn noindex(mut self, paths: &[&str]) -> Self {
+        self.config.noindex = paths.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Insert the principal of type `P` stored in the request extensions by an upstream
+    /// authentication middleware into the context under `"user"`, unless the context
+    /// builder already set it.
+    pub fn principal<P: IntoContextValue + 'static>(mut self) -> Self {
+        self.config.principal = Some(Rc::new(|req| {
+            req.extensions().get::<P>().map(P::context_value)
+        }));
+        self
+    }
+
+    /// Insert the CSRF token of type `T` stored in the request extensions by an upstream CSRF
+    /// middleware into the context under `"csrf_token"`, unless the context builder already
+    /// set it, e.g. for `<input type="hidden" name="csrf" value="{{ csrf_token }}">`.
+    pub fn inject_csrf<T: AsRef<str> + 'static>(mut self) -> Self {
+        self.config.csrf_token = Some(Rc::new(|req| {
+            req.extensions()
+                .get::<T>()
+                .map(|token| token.as_ref().into())
+        }));
+        self
+    }
+
+    /// Move the flash messages stored in the session under [`TeraPage::FLASH_KEY`] into the
+    /// context under `"flash"`, unless the context builder already set it, so they are shown
+    /// once, e.g. on the page a form redirects to. Any serializable value can be stored.
+    /// Requires `actix-session`'s `SessionMiddleware` to be registered after (outside) this
+    /// middleware. Pages showing flash messages shouldn't be [static](Self::static_pages).
+    #[cfg(feature = "actix-session")]
+    pub fn flash(mut self, enabled: bool) -> Self {
+        self.config.flash = enabled;
+        self
+    }
+
+    /// Treat pages for the given paths, and any path beneath them, as static: they are
+    /// rendered once, served from a cache afterwards and support `Range` requests.
+    /// The context builder only runs for the first render, and compressed pages are only
+    /// compressed once per encoding. Each path, locale, device class
+    /// and A/B variant is cached apart. Pages that would carry the principal, a CSRF token, a
+    /// request ID or flash messages are rendered for every request and never cached.
+    #[cfg(feature = "static-cache")]
+    pub fn static_pages(mut self, paths: &[&str]) -> Self {
+        self.config.static_pages = paths.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Send static pages with a `Cache-Control` header allowing `max_age` of freshness and
+    /// `stale_window` of `stale-while-revalidate`, and apply the same policy to the static
+    /// page cache: once a cached page is older than `max_age`, it is still served for up to
+    /// `stale_window` longer while it is re-rendered in the background. Older pages are
+    /// rendered before responding.
+    #[cfg(feature = "static-cache")]
+    pub fn swr(mut self, max_age: Duration, stale_window: Duration) -> Self {
+        self.config.swr = Some(Swr {
+            max_age,
+            stale_window,
+            revalidating: Default::default(),
+        });
+        self
+    }
+
+    /// Keep the last good render of every page and, when a later context build for it
+    /// fails, serve that instead of a 500, marked with `Warning: 110 - "Response is Stale"`.
+    /// Snapshots are kept per path, locale, device class and A/B variant like
+    /// [static pages](TeraPage::static_pages), and shared by all requests for them, so pages
+    /// the middleware would put a principal, CSRF token, request ID or flash messages into
+    /// are never kept; pages carrying other per-user data should be marked with
+    /// [`TeraPage::auto_private`], which keeps them out.
+    /// Context builders fail by returning an error from
+    /// [`try_context_builder`](TeraPageBuilder::try_context_builder).
+    #[cfg(feature = "static-cache")]
+    pub fn serve_stale_on_error(mut self, enabled: bool) -> Self {
+        self.config.stale = enabled.then(cache::RenderCache::default);
+        self
+    }
+
+    /// Replace template names with an opaque hash in log output and error responses,
+    /// so the internal layout of the template directory isn't exposed. This covers every
+    /// template named in a render error, such as the parents and includes of the page.
+    pub fn redact_template_paths(mut self, redact: bool) -> Self {
+        self.config.redact_template_paths = redact;
+        self
+    }
+
+    /// Read a TOML file mapping template names to `Cache-Control` values, applied to the
+    /// responses of those templates:
+    ///
+    /// ```toml
+    /// "pages/about.html" = "max-age=300"
+    /// ```
+    ///
+    /// A missing file is not an error and leaves caching headers unset.
+    #[cfg(feature = "cache-config")]
+    pub fn cache_config(mut self, path: &str) -> Result<Self, ConfigError> {
+        let error = |message: String| ConfigError::CacheConfig {
+            path: path.to_string(),
+            message,
+        };
+
+        let source = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No cache config found at {:?}.", path);
+                return Ok(self);
+            }
+            Err(e) => return Err(error(e.to_string())),
+        };
+
+        self.config.cache_control = toml::from_str(&source).map_err(|e| error(e.to_string()))?;
+        Ok(self)
+    }
+
+    /// Only serve pages while `flag` is set; when it is cleared, every request falls
+    /// through to the inner service. The flag can be flipped at runtime.
+    pub fn enabled(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.config.enabled = Some(flag);
+        self
+    }
+
+    /// Assemble every page from several templates rendered with the same context and
+    /// concatenated in order. The matched page is inserted where the list contains
+    /// [`TeraPage::COMPOSED_PAGE`], or appended if it doesn't:
+    ///
+    /// ```no_run
+    /// # use actix_tera_page::TeraPage;
+    /// # use actix_web::HttpRequest;
+    /// # use tera::Context;
+    /// # async fn base_context(_: HttpRequest) -> Context { Context::new() }
+    /// TeraPage::new("pages", base_context).compose(&[
+    ///     "partials/header.html",
+    ///     TeraPage::COMPOSED_PAGE,
+    ///     "partials/footer.html",
+    /// ]);
+    /// ```
+    pub fn compose(mut self, fragments: &[&str]) -> Self {
+        self.config.compose = fragments.iter().map(|f| f.to_string()).collect();
+        self
+    }
+
+    /// Let templates redirect by rendering `REDIRECT: /new-path` as their first line, which
+    /// is answered with a `302 Found` to that location instead of the rendered body.
+    pub fn allow_template_redirects(mut self, allow: bool) -> Self {
+        self.config.template_redirects = allow;
+        self
+    }
+
+    /// Autoescape pages even when Tera isn't configured to for their extension. Tera decides
+    /// this by file suffix (`.html`, `.htm` and `.xml` by default), so pages served with
+    /// another [`extension`](Self::extension), such as `.md` producing HTML, are rendered
+    /// with a copy of the `Tera` that also autoescapes it. The copy is made once per worker,
+    /// and again whenever the served `Tera` changes, such as after a reload.
+    pub fn force_autoescape(mut self, force: bool) -> Self {
+        self.config.force_autoescape = force;
+        self
+    }
+
+    /// A handle to the counters updated by this instance's middleware.
+    pub fn metrics(&self) -> TeraPageMetrics {
+        self.config.metrics.clone()
+    }
+
+    /// Record into an existing set of counters, e.g. one shared by every worker.
+    pub fn with_metrics(mut self, metrics: TeraPageMetrics) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// Choose how to resolve paths that match more than one template. Defaults to
+    /// [`Ambiguity::LastWins`].
+    pub fn on_ambiguous(mut self, ambiguity: Ambiguity) -> Self {
+        self.config.ambiguity = ambiguity;
+        self
+    }
+
+    /// Insert the matched template's name and, when Tera loaded it from disk, its file path
+    /// into the context as `template_source.name` and `template_source.path`, e.g. for
+    /// "edit this page" links. This exposes the template layout, so only enable it in
+    /// trusted or admin builds.
+    pub fn inject_template_source(mut self, inject: bool) -> Self {
+        self.config.inject_template_source = inject;
+        self
+    }
+
+    /// Look up `Tera` as app data of type `Data<T>` instead of `Data<Tera>`, for apps that
+    /// register it inside a newtype.
+    pub fn tera_type<T: AsRef<Tera> + Send + Sync + 'static>(mut self) -> Self {
+        self.config.tera_lookup = Rc::new(|req| {
+            req.app_data::<Data<T>>()
+                .map(|data| Arc::new(WrappedTera(data.clone())) as TeraHandle)
+        });
+        self
+    }
+
+    /// Look up `Tera` as app data of type `Data<ArcSwap<Tera>>`, so that it can be replaced
+    /// at runtime, e.g. by [`TeraPage::reload_handler`].
+    pub fn reloadable_tera(mut self) -> Self {
+        #[cfg(feature = "static-cache")]
+        let generation = self.config.generation.clone();
+        self.config.tera_lookup = Rc::new(move |req| {
+            let tera = req.app_data::<Data<ArcSwap<Tera>>>()?.load_full();
+            #[cfg(feature = "static-cache")]
+            generation.observe(&tera);
+            Some(Arc::new(tera) as TeraHandle)
+        });
+        self
+    }
+
+    /// Render with whichever `Tera` is currently stored in `active`, instead of looking it
+    /// up in the app data, so that operators can switch template sets atomically (e.g. for
+    /// blue/green rollouts) by storing a new one. Reading it costs one atomic load per
+    /// request; static pages are re-rendered after a switch.
+    pub fn active_tera(mut self, active: Arc<ArcSwap<Tera>>) -> Self {
+        #[cfg(feature = "static-cache")]
+        let generation = self.config.generation.clone();
+        self.config.tera_lookup = Rc::new(move |_| {
+            let tera = active.load_full();
+            #[cfg(feature = "static-cache")]
+            generation.observe(&tera);
+            Some(Arc::new(tera) as TeraHandle)
+        });
+        self
+    }
+
+    /// Handler reloading the templates of a [`TeraPage::reloadable_tera`] from disk with
+    /// [`Tera::full_reload`], replacing the `Tera` once all of them parse. Static pages are
+    /// re-rendered afterwards. Mount it at an admin route behind authentication:
+    ///
+    /// ```no_run
+    /// # use actix_tera_page::TeraPage;
+    /// # use actix_web::web;
+    /// # let admin = web::scope("/_admin");
+    /// admin.route("/reload", web::post().to(TeraPage::reload_handler));
+    /// ```
+    pub async fn reload_handler(tera: Data<ArcSwap<Tera>>) -> HttpResponse {
+        let mut reloaded = Tera::clone(&tera.load());
+        if let Err(e) = reloaded.full_reload() {
+            let message = error_chain(&e);
+            error!("Failed to reload templates: {}", message);
+            return HttpResponse::InternalServerError()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("Failed to reload templates: {}", message));
+        }
+
+        tera.store(Arc::new(reloaded));
+
+        debug!("Reloaded templates.");
+        HttpResponse::NoContent().finish()
+    }
+
+    /// Choose the template prefix by subdomain. `resolve` receives all but the last two labels
+    /// of the host (`brand-a` for `brand-a.example.com`) and returns the prefix to use, e.g.
+    /// `pages/brand-a`, or `None` to use the default prefix. The public suffix list isn't
+    /// consulted, so under a two-label suffix `brand-a.example.co.uk` gives `brand-a.example`.
+    /// Hosts with fewer than three labels and IP addresses always use the default prefix.
+    pub fn subdomain_prefix<R>(mut self, resolve: R) -> Self
+    where
+        R: Fn(&str) -> Option<String> + 'static,
+    {
+        self.config.subdomain_prefix = Some(Rc::new(resolve));
+        self
+    }
+
+    /// Send `Cache-Control: private, no-store` with pages whose context sets
+    /// [`TeraPage::PRIVATE_KEY`] to `true`, so context builders can mark per-user pages as
+    /// unfit for shared caches. Such pages are also kept out of the static page cache.
+    pub fn auto_private(mut self, enabled: bool) -> Self {
+        self.config.auto_private = enabled;
+        self
+    }
+
+    /// Look for pages in a subdirectory of the prefix named after the request's host first,
+    /// e.g. `pages/example.com/about.html` for `example.com/about`, before any
+    /// [`theme_chain`](Self::theme_chain) and then `pages/about.html`. The port is ignored,
+    /// and hosts with characters other than ASCII letters, digits, `-` and `.` are not
+    /// namespaced.
+    pub fn host_namespacing(mut self, enabled: bool) -> Self {
+        self.config.host_namespacing = enabled;
+        self
+    }
+
+    /// Look for pages in theme subdirectories of the prefix first: `chain` returns the themes
+    /// to try for a request, in order, e.g. `["acme", "default"]` to try
+    /// `pages/acme/about.html`, then `pages/default/about.html`, then `pages/about.html`.
+    /// Themes naming a parent directory are skipped.
+    pub fn theme_chain<F>(mut self, chain: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> Vec<String> + 'static,
+    {
+        self.config.theme_chain = Some(Rc::new(chain));
+        self
+    }
+
+    /// Set the status of rendered pages (`200 OK` by default), e.g. `201 Created` for
+    /// confirmation pages.
+    pub fn success_status(mut self, status: StatusCode) -> Self {
+        self.config.success_status = status;
+        self
+    }
+
+    /// Answer page requests matching `predicate` (e.g. inspecting a geo header set upstream)
+    /// with `451 Unavailable For Legal Reasons`, rendering `template` if Tera has it or a
+    /// plain default body otherwise.
+    pub fn legal_block<P>(mut self, predicate: P, template: &str) -> Self
+    where
+        P: Fn(&HttpRequest) -> bool + 'static,
+    {
+        self.config.legal_block = Some(LegalBlock {
+            predicate: Rc::new(predicate),
+            template: template.to_string(),
+        });
+        self
+    }
+
+    /// While `flag` is set, answer every page request, whatever its path, with `template`
+    /// and `503 Service Unavailable`, asking clients to retry after five minutes. Paths can
+    /// be exempted with [`TeraPage::maintenance_allowlist`].
+    pub fn maintenance(mut self, flag: Arc<AtomicBool>, template: &str) -> Self {
+        self.config.maintenance = Some(Maintenance {
+            flag,
+            template: template.to_string(),
+        });
+        self
+    }
+
+    /// Keep handling the given paths, and any path beneath them, normally during
+    /// maintenance (e.g. `/health`).
+    pub fn maintenance_allowlist(mut self, paths: &[&str]) -> Self {
+        self.config.maintenance_allowlist = paths.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Always pass requests for the given paths, and any path beneath them, to the inner
+    /// service. Defaults to `/.well-known`.
+    pub fn skip_prefixes(mut self, prefixes: &[&str]) -> Self {
+        self.config.skip_prefixes = prefixes.iter().map(|p| p.to_string()).collect();
+        self
+    }
+
+    /// Pass requests for paths with more than `depth` segments (e.g. 3 for `/a/b/c`) to the
+    /// inner service without looking for a template, bounding the work done for deep paths.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.config.max_depth = Some(depth);
+        self
+    }
+
+    /// Answer `OPTIONS` requests for paths matching a template with `204 No Content` and an
+    /// `Allow` header listing the methods pages are served for, instead of passing them to
+    /// the inner service.
+    pub fn handle_options(mut self, handle: bool) -> Self {
+        self.config.handle_options = handle;
+        self
+    }
+
+    /// Pass requests for paths with one of the given extensions, such as `/logo.png`, to the
+    /// inner service without looking for a template, however paths are matched. Defaults
+    /// to common image, font, stylesheet and script extensions.
+    pub fn skip_extensions(mut self, extensions: &[&str]) -> Self {
+        self.config.skip_extensions = extensions
+            .iter()
+            .map(|e| e.trim_start_matches('.').to_string())
+            .collect();
+        self
+    }
+
+    /// Render pages on actix-web's blocking thread pool, answering with `503 Service
+    /// Unavailable` when a render takes longer than `timeout`, so that pathological templates
+    /// can't stall the worker. Renders that time out still run to completion on their thread.
+    pub fn render_timeout(mut self, timeout: Duration) -> Self {
+        self.config.render_timeout = Some(timeout);
+        self
+    }
+
+    /// Render pages on actix-web's blocking thread pool instead of the worker's event loop,
+    /// so CPU-heavy renders don't hold up other requests on the worker. Each render then
+    /// pays for a hand-off to another thread and back, around 10µs per page in
+    /// `examples/render_latency.rs`, and only gains when there are spare cores to render
+    /// on. Always the case with [`render_timeout`](Self::render_timeout).
+    pub fn render_on_blocking(mut self, enabled: bool) -> Self {
+        self.config.render_on_blocking = enabled;
+        self
+    }
+
+    /// Allow at most `limit` pages to be built and rendered at once by each worker. Further
+    /// requests wait for a slot, unless [`TeraPage::fail_fast`] is set.
+    pub fn max_concurrent_renders(mut self, limit: usize) -> Self {
+        self.config.render_limit = Some(Arc::new(Semaphore::new(limit)));
+        self
+    }
+
+    /// Answer with `503 Service Unavailable` instead of waiting when the concurrent render
+    /// limit is reached.
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.config.fail_fast = fail_fast;
+        self
+    }
+
+    /// Bucket requests into experiments: `resolve` returns a variant name (e.g. from a
+    /// cookie), and `pages/landing.{variant}.html` is served in place of `pages/landing.html`
+    /// when it exists. The served variant is inserted into the context under `"ab_variant"`,
+    /// and pages are sent with `Vary: Cookie`.
+    pub fn ab_variant<R>(mut self, resolve: R) -> Self
+    where
+        R: Fn(&HttpRequest) -> Option<String> + 'static,
+    {
+        self.config.ab_variant = Some(Rc::new(resolve));
+        self
+    }
+
+    /// Serve templates by device class: `resolve` returns a class (e.g. `mobile`, from the
+    /// `User-Agent` or `Sec-CH-UA-Mobile` headers), and `pages/about.{class}.html` is served
+    /// in place of `pages/about.html` when it exists. The class is inserted into the context
+    /// under `"device"`, and pages are sent with `Vary: User-Agent`.
+    pub fn device_variant<R>(mut self, resolve: R) -> Self
+    where
+        R: Fn(&HttpRequest) -> Option<String> + 'static,
+    {
+        self.config.device_variant = Some(Rc::new(resolve));
+        self
+    }
+
+    /// Choose how requests for the prefix as a path, such as `/pages`, are handled. Defaults
+    /// to [`PrefixPath::Page`].
+    pub fn prefix_path_behavior(mut self, behavior: PrefixPath) -> Self {
+        self.config.prefix_path = behavior;
+        self
+    }
+
+    /// Collapse runs of slashes in request paths before matching them to templates, so
+    /// `/blog//post` renders `pages/blog/post.html`. Enabled by default.
+    pub fn collapse_slashes(mut self, collapse: bool) -> Self {
+        self.config.collapse_slashes = collapse;
+        self
+    }
+
+    /// Instead of rendering pages for paths with runs of slashes, permanently redirect them
+    /// to the collapsed path, with `308 Permanent Redirect`. Only applies while
+    /// [`collapse_slashes`](Self::collapse_slashes) is enabled.
+    pub fn redirect_collapsed_slashes(mut self, redirect: bool) -> Self {
+        self.config.redirect_slashes = redirect;
+        self
+    }
+
+    /// Serve `pages/about.{suffix}.html` in place of `pages/about.html`, when it exists, to
+    /// requests carrying the cookie `cookie_name`, e.g. one set when JavaScript is found to
+    /// be unavailable. Pages are sent with `Vary: Cookie`.
+    pub fn nojs_variant(mut self, cookie_name: &str, suffix: &str) -> Self {
+        self.config.nojs = Some((
+            cookie_name.to_string(),
+            suffix.trim_matches('.').to_string(),
+        ));
+        self
+    }
+
+    /// Rewrite request paths before matching them to templates, e.g. to strip a version
+    /// segment so `/v2/about` renders `pages/about.html`. Only matching sees the rewritten
+    /// path: requests that fall through reach the inner service unchanged.
+    pub fn rewrite_path<F>(mut self, rewrite: F) -> Self
+    where
+        F: for<'a> Fn(&'a str) -> Cow<'a, str> + 'static,
+    {
+        self.config.rewrite_path = Some(Rc::new(rewrite));
+        self
+    }
+
+    /// Permanently redirect each path in `aliases` to the path it maps to, e.g. `/old-about`
+    /// to `/about` after a URL migration, with `308 Permanent Redirect`. The query string is
+    /// carried over unless disabled with [`TeraPage::preserve_query_on_redirect`]. Aliases
+    /// are resolved before any template matching.
+    pub fn aliases(mut self, aliases: HashMap<String, String>) -> Self {
+        self.config.aliases = aliases;
+        self
+    }
+
+    /// Carry the query string of requests over to the redirects the middleware issues for
+    /// them, such as for [`TeraPage::aliases`]. Enabled by default. Redirects rendered by
+    /// templates keep the location they give.
+    pub fn preserve_query_on_redirect(mut self, preserve: bool) -> Self {
+        self.config.preserve_query = preserve;
+        self
+    }
+
+    /// Serve `/robots.txt` and `/sitemap.xml` from the `robots.txt` and `sitemap.xml`
+    /// templates under the prefix, as `text/plain` and `application/xml`.
+    pub fn seo_files(mut self, enabled: bool) -> Self {
+        self.config.seo_files = enabled;
+        self
+    }
+
+    /// Insert the canonical path of each page into the context as `canonical`, for use in
+    /// `<link rel="canonical">` tags. It is the path the page was matched with, after
+    /// [`rewrite_path`](Self::rewrite_path) and with runs of slashes collapsed. Pages backed
+    /// by index templates canonicalize with or without a trailing slash according to
+    /// `slash`; other pages never have one.
+    pub fn index_canonical(mut self, slash: TrailingSlash) -> Self {
+        self.config.index_canonical = Some(slash);
+        self
+    }
+
+    /// Render requests for paths under each key of `teras`, such as `/docs`, with that
+    /// `Tera` instead of the registered one. The longest matching prefix wins; other paths
+    /// use the registered `Tera`.
+    pub fn tera_for_prefix(mut self, teras: HashMap<String, Arc<Tera>>) -> Self {
+        let mut teras = teras.into_iter().collect::<Vec<_>>();
+        teras.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.trim_end_matches('/').len()));
+        self.config.section_teras = teras;
+        self
+    }
+
+    /// Build the responses of HTML pages with `actix-web-lab`'s `Html` responder, so they
+    /// carry the same content type as other `Html` responses in the app. Compressed and
+    /// ranged responses are built as usual.
+    #[cfg(feature = "lab")]
+    pub fn lab_html(mut self, enabled: bool) -> Self {
+        self.config.lab_html = enabled;
+        self
+    }
+
+    /// Send HTML pages with a `Content-Security-Policy` allowing scripts and styles from the
+    /// site's own origin, plus exactly the inline `<script>` and `<style>` blocks of the page,
+    /// listed by the SHA-256 hashes of their content. Inline event handlers and `style`
+    /// attributes are not covered, so pages relying on them are blocked.
+    #[cfg(feature = "csp")]
+    pub fn csp_hashes(mut self, enabled: bool) -> Self {
+        self.config.csp_hashes = enabled;
+        self
+    }
+
+    /// Strip comments and collapse whitespace in rendered pages, leaving the content of
+    /// `<pre>`, `<textarea>`, `<script>` and `<style>` elements as it is.
+    pub fn minify(mut self, minify: bool) -> Self {
+        self.config.minify = minify;
+        self
+    }
+
+    /// Tag pages with the version token `etag` returns for their template and request,
+    /// such as a content hash from a CMS. Requests whose `If-None-Match` has the same tag
+    /// are answered with `304 Not Modified` before the page is rendered. Compressed pages get
+    /// the encoding appended to their tag, e.g. `"v1-br"`, as each encoding is a different
+    /// representation of the page.
+    pub fn etag_fn<F>(mut self, etag: F) -> Self
+    where
+        F: Fn(&str, &HttpRequest) -> Option<String> + 'static,
+    {
+        self.config.etag = Some(Rc::new(etag));
+        self
+    }
+
+    /// Load a fresh `Tera` from `glob` for every request instead of using the registered
+    /// one, so template edits show up without a restart. Load errors are returned as the
+    /// response body. Meant for development only: it is slow, and any customization of
+    /// the registered `Tera` (filters, autoescaping) is not carried over.
+    pub fn dev_glob(mut self, glob: &str) -> Self {
+        self.config.dev_glob = Some(glob.to_string());
+        self
+    }
+
+    /// Call `hook` with the path and the template candidates tried whenever a page request
+    /// matches no template and falls through to the inner service.
+    pub fn on_fallthrough<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str, &[String]) + 'static,
+    {
+        self.config.on_fallthrough = Some(Rc::new(hook));
+        self
+    }
+
+    /// Route paths to templates with glob or regex rules instead of by name. Paths matching
+    /// no rule are still looked up by name. Routed templates are subject to
+    /// [`ignore_pattern`](Self::ignore_pattern) like any other.
+    pub fn match_strategy(mut self, strategy: MatchStrategy) -> Self {
+        self.config.match_strategy = strategy;
+        self
+    }
+
+    /// Serve a page as a download when `filename` returns a name for its template and
+    /// request, setting `Content-Disposition: attachment` with that name.
+    pub fn attachment<F>(mut self, filename: F) -> Self
+    where
+        F: Fn(&str, &HttpRequest) -> Option<String> + 'static,
+    {
+        self.config.attachment = Some(Rc::new(filename));
+        self
+    }
+
+    /// Pass every rendered page through `hook`, which may await other services (e.g. to
+    /// inline critical CSS) and returns the body to send instead. Responses wait for the
+    /// hook while holding their [`max_concurrent_renders`](Self::max_concurrent_renders)
+    /// permit, so slow hooks add directly to page latency. Static pages are cached after the
+    /// hook has run, so it only delays their first render. Pages rendered by
+    /// [`warm_cache`](Self::warm_cache) run it on their first request, as it needs one.
+    pub fn post_render_async<H, F>(mut self, hook: H) -> Self
+    where
+        H: Fn(String, &HttpRequest) -> F + 'static,
+        F: Future<Output = String> + 'static,
+    {
+        self.config.post_render = Some(Rc::new(move |body, req| Box::pin(hook(body, req))));
+        self
+    }
+
+    /// Build the response for `template` with `handler` instead, which receives the request
+    /// and the rendered body, e.g. to set headers depending on database state. The page's
+    /// usual headers are not added. Can be called repeatedly, once per template.
+    pub fn override_handler<H>(mut self, template: &str, handler: H) -> Self
+    where
+        H: Fn(HttpRequest, String) -> HttpResponse + 'static,
+    {
+        self.config
+            .overrides
+            .insert(template.to_string(), Rc::new(handler));
+        self
+    }
+
+    /// Attach the cookies returned by `cookies` to every rendered page, e.g. a CSRF token.
+    /// Cookies without a `SameSite` attribute get `Lax`, and those without `Secure` get it
+    /// when the request was made over HTTPS.
+    pub fn set_cookies<F>(mut self, cookies: F) -> Self
+    where
+        F: Fn(&HttpRequest) -> Vec<Cookie<'static>> + 'static,
+    {
+        self.config.set_cookies = Some(Rc::new(cookies));
+        self
+    }
+
+    /// Never serve templates under the prefix with a path segment matching `pattern`, where
+    /// `*` matches any run of characters: `_*` hides partials like `pages/_nav.html`, and
+    /// `partials` hides everything in `pages/partials/`. Can be called repeatedly.
+    pub fn ignore_pattern(mut self, pattern: &str) -> Self {
+        self.config.ignore_patterns.push(pattern.to_string());
+        self
+    }
+
+    /// Report the middleware's events to `logger` instead of the `log` crate.
+    pub fn logger(mut self, logger: Arc<dyn TeraPageLogger>) -> Self {
+        self.config.logger = Some(logger);
+        self
+    }
+
+    /// Tag each request with an ID, inserted into the context as `request_id` and echoed in
+    /// the `header` response header. The ID is taken from a [`RequestId`] extension set by
+    /// an earlier middleware, then from the `header` request header, and is generated if
+    /// neither is present.
+    pub fn request_id(mut self, header: &str) -> Self {
+        self.config.request_id = Some(
+            HeaderName::from_bytes(header.as_bytes())
+                .expect("request ID header must be a valid header name"),
+        );
+        self
+    }
+
+    /// Transform the template candidates for each request before matching, e.g. to
+    /// lowercase them or map aliases. Each stage receives the previous stage's output for a
+    /// group of candidates and returns the replacement, in priority order.
+    pub fn candidate_pipeline(mut self, stages: Vec<CandidateTransform>) -> Self {
+        self.config.pipeline = stages.into_iter().map(CandidateStage::from).collect();
+        self
+    }
+
+    /// Serve directories that have no index template but do have child pages with the
+    /// `listing` template, which receives the children's URLs as `entries`. `None`
+    /// disables listings.
+    pub fn autoindex(mut self, listing: Option<&str>) -> Self {
+        self.config.autoindex = listing.map(str::to_string);
+        self
+    }
+
+    /// Treat requests sent with `Cache-Control: no-cache` for which `trusted` returns true
+    /// as wanting a fresh page: the shared context is rebuilt for them and the static page
+    /// cache is not consulted. Browsers send the header on every hard reload, and a rebuild
+    /// replaces the shared context for the whole worker, so `trusted` should only accept
+    /// requests allowed to cause that work, e.g. ones from signed-in editors.
+    pub fn honor_request_no_cache<P>(mut self, trusted: P) -> Self
+    where
+        P: Fn(&HttpRequest) -> bool + 'static,
+    {
+        self.config.honor_no_cache = Some(Rc::new(trusted));
+        self
+    }
+
+    /// Let an upstream proxy pick the page by naming its template in the `header` request
+    /// header, bypassing path matching. Only registered templates under the prefix are accepted;
+    /// other values are ignored and the path is matched as usual. `None` disables this.
+    pub fn template_header(mut self, header: Option<&str>) -> Self {
+        self.config.template_header = header.map(|h| {
+            HeaderName::from_bytes(h.as_bytes())
+                .expect("template header must be a valid header name")
+        });
+        self
+    }
+
+    /// Map template names to assets the browser should preload, each sent as a
+    /// `Link: <url>; rel=preload` header on that template's responses. The `as` type is
+    /// derived from the asset's extension.
+    pub fn preload_map(mut self, map: HashMap<String, Vec<String>>) -> Self {
+        self.config.preload = map;
+        self
+    }
+
+    /// Render the pages for `paths` with `tera` and store them in the static page cache when
+    /// the middleware starts, ahead of serving traffic. Pages are rendered with `context`
+    /// merged over the [`globals`](Self::globals); there is no request, so the context
+    /// builder isn't called and no request values are injected. The
+    /// [`post_render_async`](Self::post_render_async) hook runs on the first request for each
+    /// page instead. The middleware fails to start if a page fails to render. The paths
+    /// should be covered by [`TeraPage::static_pages`], as the cache isn't consulted for
+    /// other paths. Warmed pages are cached for the default locale, so they only serve
+    /// requests without a device class or A/B variant.
+    #[cfg(feature = "static-cache")]
+    pub fn warm_cache(mut self, tera: &Tera, context: &Context, paths: &[&str]) -> Self {
+        self.config.warm = Some((
+            Arc::new(Box::new(tera.clone())),
+            context.clone(),
+            paths.iter().map(|p| p.to_string()).collect(),
+        ));
+        self
+    }
+
+    /// Decide from the built context whether to render each page, e.g. answering with a 404
+    /// when the dataset it lists is empty. `gate` receives the context and the template
+    /// name, and returns [`PageOutcome::Render`] with the context to render (usually the
+    /// one given), or another outcome to respond with instead.
+    pub fn context_gate<G>(mut self, gate: G) -> Self
+    where
+        G: Fn(&Context, &str) -> PageOutcome + 'static,
+    {
+        self.config.context_gate = Some(Rc::new(gate));
+        self
+    }
+
+    /// Merge fixed values, such as configuration constants, into every render. They sit
+    /// beneath the shared context and the per-request context, either of which can
+    /// override them.
+    pub fn globals(mut self, globals: Context) -> Self {
+        self.config.globals = globals;
+        self
+    }
+
+    /// Match against a precomputed set of template names instead of asking Tera, for
+    /// template sets large enough that enumerating them per request is costly. The index is
+    /// authoritative: templates missing from it are never matched.
+    pub fn template_index(mut self, index: Arc<HashSet<String>>) -> Self {
+        self.config.template_index = Some(index);
+        self
+    }
+
+    /// Insert the request path into the context as `path`, and as `normalized_path` with
+    /// runs of slashes collapsed and any trailing slash removed (`/blog/` becomes `/blog`),
+    /// e.g. for highlighting the active navigation link. Keys the context builder already set
+    /// are left alone.
+    pub fn inject_path(mut self, inject: bool) -> Self {
+        self.config.inject_path = inject;
+        self
+    }
+
+    /// Rename the context keys request-derived values are inserted under, e.g. to move
+    /// `locale` out of the way of a value the context builder already uses:
+    ///
+    /// ```
+    /// # use actix_tera_page::{InjectionKeys, TeraPage};
+    /// # use actix_web::HttpRequest;
+    /// # use tera::Context;
+    /// # async fn base_context(_: HttpRequest) -> Context { Context::new() }
+    /// let page = TeraPage::new("pages", base_context).injection_keys(InjectionKeys {
+    ///     locale: "lang".to_string(),
+    ///     ..InjectionKeys::default()
+    /// });
+    /// ```
+    pub fn injection_keys(mut self, keys: InjectionKeys) -> Self {
+        self.config.keys = keys;
+        self
+    }
+
+    /// Insert build metadata, such as the version and git SHA, into every context under
+    /// `build`, e.g. for a footer identifying the deployed build. See [`build_info!`].
+    pub fn build_info(mut self, info: BuildInfo) -> Self {
+        self.config.build_info = Some(info);
+        self
+    }
+
+    /// Merge a long-lived context into every render, beneath the per-request context.
+    /// It is rebuilt by `refresh` every `refresh_interval` on a task spawned on the current
+    /// runtime; until the first refresh completes it is empty.
+    ///
+    /// The task stops once the middleware is dropped, or when the runtime shuts down.
+    ///
+    /// `TeraPage` is built once per worker by the `HttpServer` factory, so every worker runs
+    /// its own task and keeps its own copy of the context: `refresh` is called once per
+    /// worker each interval, and workers may briefly disagree after a refresh.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of an actix runtime (e.g. outside the `HttpServer` factory).
+    pub fn shared_context<R, Fut>(mut self, refresh_interval: Duration, refresh: R) -> Self
+    where
+        R: Fn() -> Fut + 'static,
+        Fut: Future<Output = Context> + 'static,
+    {
+        let refresh: ContextRefresh = Rc::new(move || Box::pin(refresh()));
+        let shared = Arc::new(ArcSwap::from_pointee(Context::new()));
+        let weak = Arc::downgrade(&shared);
+        let task_refresh = refresh.clone();
+
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(refresh_interval);
+            loop {
+                interval.tick().await;
+                if weak.strong_count() == 0 {
+                    break;
+                }
+
+                let context = task_refresh().await;
+                match weak.upgrade() {
+                    Some(shared) => shared.store(Arc::new(context)),
+                    None => break,
+                }
+            }
+        });
+
+        self.config.shared_context = Some(SharedContext {
+            current: shared,
+            refresh,
+        });
+        self
+    }
+}
+
+/// Builder for a [`TeraPage`], setting only what `TeraPage`'s constructors take: the
+/// template prefix, extension and index name, and the context builder. Every other option,
+/// including the rest of how paths map to templates, is set on the built `TeraPage`:
+///
+/// ```
+/// # use actix_tera_page::TeraPage;
+/// # use actix_web::HttpRequest;
+/// # use tera::Context;
+/// # async fn base_context(_: HttpRequest) -> Context { Context::new() }
+/// let page = TeraPage::builder()
+///     .prefix("content")
+///     .extension("tera")
+///     .index_name("_index")
+///     .context_builder(base_context)
+///     .build()
+///     .noindex(&["/drafts"]);
+/// ```
+pub struct TeraPageBuilder {
+    page: TeraPage,
+}
+
+impl Default for TeraPageBuilder {
+    fn default() -> Self {
+        TeraPageBuilder {
+            page: TeraPage {
+                context_builder: Rc::new(|_| {
+                    Box::pin(ready(Ok(PageOutcome::Render(Context::new()))))
+                }),
+                config: Config::new("pages"),
+            },
+        }
+    }
+}
+
+impl TeraPageBuilder {
+    /// Set the prefix templates are looked up under (`pages` by default).
+    pub fn prefix(mut self, template_prefix: &str) -> Self {
+        self.page.config.template_prefix = template_prefix.trim_matches('/').to_string();
+        self
+    }
+
+    /// Set the function that builds the context for each request. Without one, pages are
+    /// rendered with an empty context.
+    pub fn context_builder<C, F>(mut self, context_builder: C) -> Self
+    where
+        C: Fn(HttpRequest) -> F + 'static,
+        F: Future<Output = Context> + 'static,
+    {
+        self.page.context_builder = Rc::new(move |req| {
+            let context = context_builder(req);
+            Box::pin(async move { Ok(PageOutcome::Render(context.await)) })
+        });
+        self
+    }
+
+    /// Set a function that builds the context for each request and may fail, e.g. when a
+    /// database query does. Errors are answered with a 500, or with the last good render
+    /// under [`TeraPage::serve_stale_on_error`].
+    pub fn try_context_builder<C, F, E>(mut self, context_builder: C) -> Self
+    where
+        C: Fn(HttpRequest) -> F + 'static,
+        F: Future<Output = Result<Context, E>> + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        self.page.context_builder = Rc::new(move |req| {
+            let context = context_builder(req);
+            Box::pin(async move {
+                match context.await {
+                    Ok(context) => Ok(PageOutcome::Render(context)),
+                    Err(e) => Err(tera::Error::chain("Context builder failed", e)),
+                }
+            })
+        });
+        self
+    }
+
+    /// Set a function that builds a serializable value for each request, such as a
+    /// `#[derive(Serialize)]` struct, converted with [`Context::from_serialize`]. Conversion
+    /// errors are answered with a 500.
+    pub fn serialize_builder<C, F, T>(mut self, context_builder: C) -> Self
+    where
+        C: Fn(HttpRequest) -> F + 'static,
+        F: Future<Output = T> + 'static,
+        T: Serialize,
+    {
+        self.page.context_builder = Rc::new(move |req| {
+            let value = context_builder(req);
+            Box::pin(async move { Context::from_serialize(value.await).map(PageOutcome::Render) })
+        });
+        self
+    }
+
+    /// Set a function that either builds the context for a request or declines to render,
+    /// such as to shed load with [`PageOutcome::Unavailable`].
+    pub fn outcome_builder<C, F>(mut self, outcome_builder: C) -> Self
+    where
+        C: Fn(HttpRequest) -> F + 'static,
+        F: Future<Output = PageOutcome> + 'static,
+    {
+        self.page.context_builder = Rc::new(move |req| {
+            let outcome = outcome_builder(req);
+            Box::pin(async move { Ok(outcome.await) })
+        });
+        self
+    }
+
+    /// See [`TeraPage::principal`].
+    pub fn principal<P: IntoContextValue + 'static>(mut self) -> Self {
+        self.page = self.page.principal::<P>();
+        self
+    }
+
+    /// See [`TeraPage::inject_csrf`].
+    pub fn inject_csrf<T: AsRef<str> + 'static>(mut self) -> Self {
+        self.page = self.page.inject_csrf::<T>();
+        self
+    }
+
+    /// See [`TeraPage::tera_type`].
+    pub fn tera_type<T: AsRef<Tera> + Send + Sync + 'static>(mut self) -> Self {
+        self.page = self.page.tera_type::<T>();
+        self
+    }
+
+    /// See [`TeraPage::cache_config`].
+    #[cfg(feature = "cache-config")]
+    pub fn cache_config(mut self, path: &str) -> Result<Self, ConfigError> {
+        self.page = self.page.cache_config(path)?;
+        Ok(self)
+    }
+
+    /// See [`TeraPage::prefix_for_env`].
+    pub fn prefix_for_env(
+        mut self,
+        tera: &Tera,
+        prefixes: HashMap<String, String>,
+    ) -> Result<Self, ConfigError> {
+        self.page = self.page.prefix_for_env(tera, prefixes)?;
+        Ok(self)
+    }
+
+    /// Finish building, returning the middleware. Options that weren't set keep the
+    /// defaults of [`TeraPage::new`]. Every option can still be changed on the returned
+    /// [`TeraPage`].
+    pub fn build(self) -> TeraPage {
+        self.page
+    }
+}
+
+/// Builder methods setting the option of the [`TeraPage`] method of the same name.
+macro_rules! forward_options {
+    ($(
+        $(#[$attr:meta])*
+        fn $name:ident $([$($generics:tt)*])? ($($arg:ident: $ty:ty),*)
+            $(where [$($bounds:tt)*])?;
+    )*) => {
+        impl TeraPageBuilder {
+            $(
+                #[doc = concat!("See [`TeraPage::", stringify!($name), "`].")]
+                $(#[$attr])*
+                pub fn $name $(<$($generics)*>)? (mut self, $($arg: $ty),*) -> Self
+                $(where $($bounds)*)?
+                {
+                    self.page = self.page.$name($($arg),*);
+                    self
+                }
+            )*
+        }
+    };
+}
+
+forward_options! {
+    fn static_error_page(status: StatusCode, body: &'static str);
+    fn not_found_body(content_type: &str, body: &str);
+    fn extension(extension: &str);
+    fn content_type(extension: &str, mime: &str);
+    fn index_name(index_name: &str);
+    fn fragment_suffix(suffix: &str);
+    fn htmx(htmx: HtmxConfig);
+    fn fragment_header(name: &str);
+    fn locales(supported: &[&str]);
+    fn max_body_size(limit: usize);
+    fn noindex(paths: &[&str]);
+    #[cfg(feature = "actix-session")]
+    fn flash(enabled: bool);
+    #[cfg(feature = "static-cache")]
+    fn static_pages(paths: &[&str]);
+    #[cfg(feature = "static-cache")]
+    fn swr(max_age: Duration, stale_window: Duration);
+    #[cfg(feature = "static-cache")]
+    fn serve_stale_on_error(enabled: bool);
+    fn redact_template_paths(redact: bool);
+    fn enabled(flag: Arc<AtomicBool>);
+    fn compose(fragments: &[&str]);
+    fn allow_template_redirects(allow: bool);
+    fn force_autoescape(force: bool);
+    fn with_metrics(metrics: TeraPageMetrics);
+    fn on_ambiguous(ambiguity: Ambiguity);
+    fn inject_template_source(inject: bool);
+    fn reloadable_tera();
+    fn active_tera(active: Arc<ArcSwap<Tera>>);
+    fn subdomain_prefix[R](resolve: R) where [R: Fn(&str) -> Option<String> + 'static];
+    fn auto_private(enabled: bool);
+    fn host_namespacing(enabled: bool);
+    fn theme_chain[F](chain: F) where [F: Fn(&HttpRequest) -> Vec<String> + 'static];
+    fn success_status(status: StatusCode);
+    fn legal_block[P](predicate: P, template: &str)
+        where [P: Fn(&HttpRequest) -> bool + 'static];
+    fn maintenance(flag: Arc<AtomicBool>, template: &str);
+    fn maintenance_allowlist(paths: &[&str]);
+    fn skip_prefixes(prefixes: &[&str]);
+    fn max_depth(depth: usize);
+    fn handle_options(handle: bool);
+    fn skip_extensions(extensions: &[&str]);
+    fn render_timeout(timeout: Duration);
+    fn render_on_blocking(enabled: bool);
+    fn max_concurrent_renders(limit: usize);
+    fn fail_fast(fail_fast: bool);
+    fn ab_variant[R](resolve: R) where [R: Fn(&HttpRequest) -> Option<String> + 'static];
+    fn device_variant[R](resolve: R) where [R: Fn(&HttpRequest) -> Option<String> + 'static];
+    fn prefix_path_behavior(behavior: PrefixPath);
+    fn collapse_slashes(collapse: bool);
+    fn redirect_collapsed_slashes(redirect: bool);
+    fn nojs_variant(cookie_name: &str, suffix: &str);
+    fn rewrite_path[F](rewrite: F) where [F: for<'a> Fn(&'a str) -> Cow<'a, str> + 'static];
+    fn aliases(aliases: HashMap<String, String>);
+    fn preserve_query_on_redirect(preserve: bool);
+    fn seo_files(enabled: bool);
+    fn index_canonical(slash: TrailingSlash);
+    fn tera_for_prefix(teras: HashMap<String, Arc<Tera>>);
+    #[cfg(feature = "lab")]
+    fn lab_html(enabled: bool);
+    #[cfg(feature = "csp")]
+    fn csp_hashes(enabled: bool);
+    fn minify(minify: bool);
+    fn etag_fn[F](etag: F) where [F: Fn(&str, &HttpRequest) -> Option<String> + 'static];
+    fn dev_glob(glob: &str);
+    fn on_fallthrough[F](hook: F) where [F: Fn(&str, &[String]) + 'static];
+    fn match_strategy(strategy: MatchStrategy);
+    fn attachment[F](filename: F) where [F: Fn(&str, &HttpRequest) -> Option<String> + 'static];
+    fn post_render_async[H, F](hook: H) where [
+        H: Fn(String, &HttpRequest) -> F + 'static,
+        F: Future<Output = String> + 'static,
+    ];
+    fn override_handler[H](template: &str, handler: H)
+        where [H: Fn(HttpRequest, String) -> HttpResponse + 'static];
+    fn set_cookies[F](cookies: F) where [F: Fn(&HttpRequest) -> Vec<Cookie<'static>> + 'static];
+    fn ignore_pattern(pattern: &str);
+    fn logger(logger: Arc<dyn TeraPageLogger>);
+    fn request_id(header: &str);
+    fn candidate_pipeline(stages: Vec<CandidateTransform>);
+    fn autoindex(listing: Option<&str>);
+    fn honor_request_no_cache[P](trusted: P) where [P: Fn(&HttpRequest) -> bool + 'static];
+    fn template_header(header: Option<&str>);
+    fn preload_map(map: HashMap<String, Vec<String>>);
+    #[cfg(feature = "static-cache")]
+    fn warm_cache(tera: &Tera, context: &Context, paths: &[&str]);
+    fn context_gate[G](gate: G) where [G: Fn(&Context, &str) -> PageOutcome + 'static];
+    fn globals(globals: Context);
+    fn template_index(index: Arc<HashSet<String>>);
+    fn inject_path(inject: bool);
+    fn injection_keys(keys: InjectionKeys);
+    fn build_info(info: BuildInfo);
+    fn shared_context[R, Fut](refresh_interval: Duration, refresh: R) where [
+        R: Fn() -> Fut + 'static,
+        Fut: Future<Output = Context> + 'static,
+    ];
+}
+
+/// A context builder that is `Send`, returning `Send` futures, for creating [`TeraPage`]s
+/// from code that requires thread-safe values, such as an `HttpServer` factory capturing a
+/// builder created once in `main`:
+///
+/// ```no_run
+/// # use actix_tera_page::TeraPageSend;
+/// # use actix_web::{App, HttpServer};
+/// # use tera::Context;
+/// # async fn run() -> std::io::Result<()> {
+/// let pages = TeraPageSend::new("pages", |_| async { Context::new() });
+/// HttpServer::new(move || App::new().wrap(pages.page()))
+///     .bind(("127.0.0.1", 8080))?
+///     .run()
+///     .await
+/// # }
+/// ```
+///
+/// The middleware itself still runs on each worker's thread, like the rest of actix-web's.
+#[derive(Clone)]
+pub struct TeraPageSend {
+    template_prefix: String,
+    context_builder: Arc<dyn Fn(HttpRequest) -> BoxFuture<'static, Context> + Send + Sync>,
+}
+
+impl TeraPageSend {
+    /// Create a new instance with a given template search prefix and a function that builds
+    /// the context.
+    pub fn new<C, F>(template_prefix: &str, context_builder: C) -> Self
+    where
+        C: Fn(HttpRequest) -> F + Send + Sync + 'static,
+        F: Future<Output = Context> + Send + 'static,
+    {
+        TeraPageSend {
+            template_prefix: template_prefix.to_string(),
+            context_builder: Arc::new(move |req| Box::pin(context_builder(req))),
+        }
+    }
+
+    /// Create a [`TeraPage`] building contexts with this instance's builder, on which the
+    /// remaining options can be set.
+    pub fn page(&self) -> TeraPage {
+        let context_builder = self.context_builder.clone();
+        TeraPage::new(&self.template_prefix, move |req| context_builder(req))
+    }
+}
+
+impl<S> Transform<S, ServiceRequest> for TeraPage
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>> + 'static,
+    S::Error: Into<Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = TeraPageMiddleware<S>;
+    type Future = LocalBoxFuture<'static, Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let middleware = TeraPageMiddleware {
+            service: Rc::new(service),
+            context_builder: self.context_builder.clone(),
+            config: Rc::new(self.config.clone()),
+        };
+
+        #[cfg(feature = "static-cache")]
+        if let Some((tera, context, paths)) = self.config.warm.clone() {
+            return Box::pin(async move {
+                warm_cache(&middleware.config, tera, context, &paths)
+                    .await
+                    .map_err(|_| error!("Failed to warm the static page cache."))?;
+                Ok(middleware)
+            });
+        }
+
+        Box::pin(ready(Ok(middleware)))
+    }
+}
+
+pub struct TeraPageMiddleware<S> {
+    service: Rc<S>,
+    context_builder: ContextBuilder,
+    config: Rc<Config>,
+}
+
+impl<S> TeraPageMiddleware<S> {
+    /// The template prefix for a request, which may depend on its subdomain.
+    fn template_prefix(&self, req: &ServiceRequest) -> Cow<'_, str> {
+        let prefix = self.config.subdomain_prefix.as_ref().and_then(|resolve| {
+            let host = request_host(req)?;
+            let labels = host.split('.').collect::<Vec<_>>();
+            if labels.len() <= 2 || host.parse::<IpAddr>().is_ok() {
+                return None;
+            }
+            resolve(&labels[..labels.len() - 2].join("."))
+        });
+
+        match prefix {
+            Some(prefix) => Cow::Owned(prefix.trim_matches('/').to_string()),
+            None => Cow::Borrowed(&self.config.template_prefix),
+        }
+    }
+
+    /// The template named by the configured template header, if it lies under `prefix`.
+    fn header_template(&self, req: &ServiceRequest, prefix: &str) -> Option<String> {
+        let header = self.config.template_header.as_ref()?;
+        let name = req.headers().get(header)?.to_str().ok()?;
+
+        let allowed = !name
+            .split('/')
+            .any(|s| s.is_empty() || s == "." || s == "..")
+            && (prefix.is_empty() || is_under(name, prefix) && name != prefix);
+        if !allowed {
+            warn!(
+                "Ignoring template header naming {:?} outside the prefix.",
+                name
+            );
+            return None;
+        }
+
+        Some(name.to_string())
+    }
+
+    fn is_fragment_request(&self, req: &ServiceRequest) -> bool {
+        self.config
+            .fragment_headers
+            .iter()
+            .any(|name| req.headers().contains_key(name))
+    }
+}
+
+impl<S> Service<ServiceRequest> for TeraPageMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>> + 'static,
+    S::Error: Into<Error>,
+    S::Future: 'static,
+{
+    type Response = ServiceResponse<BoxBody>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let request_id = self
+            .config
+            .request_id
+            .as_ref()
+            .and_then(|header| Some((header.clone(), assign_request_id(&req, header)?)));
+
+        let response = self.serve(req);
+        match request_id {
+            Some((header, id)) => Box::pin(async move {
+                let mut response = response.await?;
+                response.headers_mut().insert(header, id);
+                Ok(response)
+            }),
+            None => response,
+        }
+    }
+}
+
+impl<S> TeraPageMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<BoxBody>> + 'static,
+    S::Error: Into<Error>,
+    S::Future: 'static,
+{
+    /// Route a request to its page, or on to the inner service.
+    fn serve(
+        &self,
+        req: ServiceRequest,
+    ) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, Error>> {
+        let enabled = self
+            .config
+            .enabled
+            .as_ref()
+            .is_none_or(|flag| flag.load(Ordering::Relaxed));
+
+        self.config.metrics.record_request();
+
+        let skipped = self
+            .config
+            .skip_prefixes
+            .iter()
+            .any(|p| is_under(req.path(), p))
+            || extension_of(req.path()).is_some_and(|extension| {
+                self.config
+                    .skip_extensions
+                    .iter()
+                    .any(|e| e.eq_ignore_ascii_case(extension))
+            })
+            || self.config.max_depth.is_some_and(|depth| {
+                req.path().split('/').filter(|s| !s.is_empty()).count() > depth
+            });
+
+        // HEAD is answered like GET: actix-web drops the body but keeps its length, and
+        // static pages come from the cache without rendering.
+        let readable = PAGE_METHODS.contains(req.method())
+            || self.config.handle_options && req.method() == Method::OPTIONS;
+
+        if !enabled || skipped || !readable || is_upgrade(&req) {
+            return self.fall_through(req);
+        }
+
+        if let Some(target) = self.config.aliases.get(req.path()) {
+            let location = redirect_location(&self.config, &req, target);
+            debug!("Redirecting alias to {:?}.", location);
+            let response = HttpResponse::PermanentRedirect()
+                .insert_header((header::LOCATION, location))
+                .finish();
+            return Box::pin(ready(Ok(req.into_response(response))));
+        }
+
+        let tera = if let Some(glob) = &self.config.dev_glob {
+            match Tera::new(glob) {
+                Ok(tera) => Arc::new(Box::new(tera)) as TeraHandle,
+                Err(e) => {
+                    let message = error_chain(&e);
+                    error!("Failed to load templates from {:?}: {}", glob, message);
+                    let response = HttpResponse::InternalServerError()
+                        .content_type("text/plain; charset=utf-8")
+                        .body(format!(
+                            "Failed to load templates from {}: {}",
+                            glob, message
+                        ));
+                    return Box::pin(ready(Ok(req.into_response(response))));
+                }
+            }
+        } else if let Some((_, tera)) = self
+            .config
+            .section_teras
+            .iter()
+            .find(|(prefix, _)| is_under(req.path(), prefix))
+        {
+            Arc::new(tera.clone()) as TeraHandle
+        } else if let Some(tera) = (self.config.tera_lookup)(&req) {
+            tera
+        } else if let Some(response) = self.config.static_error(StatusCode::INTERNAL_SERVER_ERROR) {
+            error!("No Tera object is registered as application data.");
+            return Box::pin(ready(Ok(req.into_response(response))));
+        } else {
+            panic!("A Tera object must be registered as application data for TeraPageMiddlewear to work!");
+        };
+        let tera = self.config.autoescaping(tera);
+        // Taken with the lookup, so a page rendered with a `Tera` replaced while it renders
+        // isn't cached as current.
+        let generation = self.config.generation();
+
+        if let Some(maintenance) = &self.config.maintenance {
+            let allowed = self
+                .config
+                .maintenance_allowlist
+                .iter()
+                .any(|p| is_under(req.path(), p));
+            if maintenance.flag.load(Ordering::Relaxed) && !allowed {
+                debug!("Serving maintenance page.");
+                let config = self.config.clone();
+                let context_builder = self.context_builder.clone();
+                let template = maintenance.template.clone();
+
+                return Box::pin(async move {
+                    let status = StatusCode::SERVICE_UNAVAILABLE;
+                    let mut response =
+                        status_page(&config, &context_builder, &tera, &req, status, &template)
+                            .await;
+                    response
+                        .headers_mut()
+                        .insert(header::RETRY_AFTER, HeaderValue::from_static("300"));
+                    Ok(req.into_response(response))
+                });
+            }
+        }
+
+        let fragment = self
+            .config
+            .fragment_suffix
+            .clone()
+            .filter(|_| self.is_fragment_request(&req));
+        let ab_variant = self
+            .config
+            .ab_variant
+            .as_ref()
+            .and_then(|resolve| resolve(req.request()));
+        let device = self
+            .config
+            .device_variant
+            .as_ref()
+            .and_then(|resolve| resolve(req.request()));
+        if let Some(device) = &device {
+            req.extensions_mut().insert(DeviceClass(device.clone()));
+        }
+        let nojs = self
+            .config
+            .nojs
+            .as_ref()
+            .filter(|(cookie, _)| req.cookie(cookie).is_some())
+            .map(|(_, suffix)| suffix.clone());
+        let variants = fragment
+            .into_iter()
+            .chain(ab_variant.clone())
+            .chain(device)
+            .chain(nojs)
+            .collect::<Vec<_>>();
+
+        let index = self.config.template_index.as_deref();
+        let exists = |name: &str| match index {
+            Some(index) => index.contains(name),
+            None => tera.get_template(name).is_ok(),
+        };
+
+        let prefix = self.template_prefix(&req);
+        let header_template = self
+            .header_template(&req, &prefix)
+            .filter(|t| exists(t) && !self.config.is_ignored(&prefix, t));
+        let path = match &self.config.rewrite_path {
+            Some(rewrite) => rewrite(req.path()).into_owned(),
+            None => req.path().to_string(),
+        };
+        let path = if self.config.collapse_slashes {
+            collapse_slashes(&path)
+        } else {
+            path
+        };
+        let path = match self.config.prefix_path {
+            _ if prefix.is_empty() || path.trim_matches('/') != prefix => path,
+            PrefixPath::Page => path,
+            PrefixPath::Index => "/".to_string(),
+            PrefixPath::FallThrough => {
+                debug!("Passing request for the prefix path through.");
+                return self.fall_through(req);
+            }
+            PrefixPath::NotFound => {
+                debug!("Answering request for the prefix path with a 404.");
+                let response = HttpResponse::NotFound().finish();
+                return Box::pin(ready(Ok(req.into_response(response))));
+            }
+        };
+        req.extensions_mut().insert(MatchedPath(path.clone()));
+        let routed = match header_template {
+            Some(template) => Some(template),
+            None => self
+                .config
+                .match_strategy
+                .route(&path)
+                .filter(|(template, _)| {
+                    let ignored = self.config.is_ignored(&prefix, template);
+                    if ignored {
+                        debug!(
+                            "Ignoring routed template {:?}.",
+                            self.config.display_name(template)
+                        );
+                    }
+                    !ignored
+                })
+                .map(|(template, captures)| {
+                    req.extensions_mut().insert(Captures(captures));
+                    template
+                }),
+        };
+        let host = self
+            .config
+            .host_namespacing
+            .then(|| request_host(&req))
+            .flatten()
+            .filter(|host| {
+                !host.starts_with('.')
+                    && host
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            })
+            .map(str::to_ascii_lowercase);
+        let themes = match &self.config.theme_chain {
+            Some(chain) => chain(req.request()),
+            None => Vec::new(),
+        };
+        let themes = host.into_iter().chain(themes).collect::<Vec<_>>();
+        let candidates = match routed {
+            Some(template) => vec![vec![template]],
+            None => themes
+                .iter()
+                .map(|theme| theme.trim_matches('/'))
+                .filter(|theme| !theme.is_empty() && !theme.split('/').any(|s| s == ".."))
+                .map(|theme| match prefix.as_ref() {
+                    "" => theme.to_string(),
+                    prefix => format!("{}/{}", prefix, theme),
+                })
+                .map(|themed| self.config.candidates(&themed, &path, &variants))
+                .find(|candidates| candidates.iter().flatten().any(|c| exists(c)))
+                .unwrap_or_else(|| self.config.candidates(&prefix, &path, &variants)),
+        };
+
+        let names = candidates
+            .iter()
+            .flatten()
+            .map(|c| self.config.display_name(c));
+        match &self.config.logger {
+            Some(logger) => {
+                logger.candidates(req.path(), &names.map(Cow::into_owned).collect::<Vec<_>>())
+            }
+            None => debug!(
+                "Checking template candidates: {:?}",
+                names.collect::<Vec<_>>()
+            ),
+        }
+
+        let mut matches = candidates
+            .iter()
+            .filter_map(|group| group.iter().find(|c| exists(c)).cloned());
+        let matched_template = matches.next();
+
+        if self.config.ambiguity == Ambiguity::Error {
+            if let Some(template) = &matched_template {
+                let conflicts = matches.collect::<Vec<_>>();
+                if !conflicts.is_empty() {
+                    let names = std::iter::once(template)
+                        .chain(&conflicts)
+                        .map(|t| self.config.display_name(t))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    error!("Ambiguous templates for path {:?}: {}", req.path(), names);
+                    self.config.metrics.record_failure();
+
+                    let response = self
+                        .config
+                        .static_error(StatusCode::INTERNAL_SERVER_ERROR)
+                        .unwrap_or_else(|| {
+                            HttpResponse::InternalServerError()
+                                .body(format!("Ambiguous templates for path: {}", names))
+                        });
+                    return Box::pin(ready(Ok(req.into_response(response))));
+                }
+            }
+        }
+
+        let mut matched_template = matched_template;
+        if matched_template.is_none() {
+            if let Some(listing) = &self.config.autoindex {
+                let templates = match index {
+                    Some(index) => index.iter().map(String::as_str).collect::<Vec<_>>(),
+                    None => tera.get_template_names().collect(),
+                };
+                let visible = templates
+                    .into_iter()
+                    .filter(|t| !self.config.is_ignored(&prefix, t))
+                    .collect::<Vec<_>>();
+                let entries = autoindex_entries(&visible, &prefix, &path, &self.config.extension);
+                if !entries.is_empty() && exists(listing) {
+                    debug!("Listing {} child pages of directory.", entries.len());
+                    req.extensions_mut().insert(Autoindex(entries));
+                    matched_template = Some(listing.clone());
+                }
+            }
+        }
+
+        if let Some(template) = matched_template {
+            let name = self.config.display_name(&template);
+            match &self.config.logger {
+                Some(logger) => logger.matched(req.path(), &name),
+                None => debug!("Matched path to template: {:?}", name),
+            }
+            self.config.metrics.record_match();
+
+            if self.config.collapse_slashes
+                && self.config.redirect_slashes
+                && req.path().contains("//")
+            {
+                let target = collapse_slashes(req.path());
+                let location = redirect_location(&self.config, &req, &target);
+                debug!("Redirecting to collapsed path {:?}.", location);
+                let response = HttpResponse::PermanentRedirect()
+                    .insert_header((header::LOCATION, location))
+                    .finish();
+                return Box::pin(ready(Ok(req.into_response(response))));
+            }
+
+            if req.method() == Method::OPTIONS {
+                let allow = PAGE_METHODS
+                    .iter()
+                    .cloned()
+                    .chain([Method::OPTIONS])
+                    .collect();
+                let response = HttpResponse::NoContent()
+                    .insert_header(header::Allow(allow))
+                    .finish();
+                return Box::pin(ready(Ok(req.into_response(response))));
+            }
+
+            if let Some(variant) = ab_variant {
+                let suffix = format!(".{}.{}", variant, self.config.extension);
+                if template.ends_with(&suffix) {
+                    req.extensions_mut().insert(AbVariant(variant));
+                }
+            }
+
+            let config = self.config.clone();
+            let context_builder = self.context_builder.clone();
+            let service = self.service.clone();
+            let metrics = self.config.metrics.clone();
+            let fall_through: FallThrough = Box::new(move |req| {
+                metrics.record_fallthrough();
+                let res = service.call(req);
+                Box::pin(async move { res.await.map_err(Into::into) })
+            });
+
+            Box::pin(render_page(
+                config,
+                context_builder,
+                tera,
+                generation,
+                template,
+                req,
+                Some(fall_through),
+            ))
+        } else {
+            match &self.config.logger {
+                Some(logger) => logger.fallthrough(req.path()),
+                None => debug!("No matching template for path."),
+            }
+            if let Some(on_fallthrough) = &self.config.on_fallthrough {
+                let tried = candidates.into_iter().flatten().collect::<Vec<_>>();
+                on_fallthrough(req.path(), &tried);
+            }
+            self.fall_through(req)
+        }
+    }
+
+    /// Pass a request on to the inner service.
+    fn fall_through(
+        &self,
+        req: ServiceRequest,
+    ) -> LocalBoxFuture<'static, Result<ServiceResponse<BoxBody>, Error>> {
+        self.config.metrics.record_fallthrough();
+        let req = self.service.call(req);
+        Box::pin(async move { req.await.map_err(Into::into) })
+    }
+}
+
+/// Returns true if HTML is the client's preferred response type, or it has no preference.
+fn accepts_html(req: &HttpRequest) -> bool {
+    let preferred = match Accept::parse(req) {
+        Ok(accept) => accept.preference(),
+        Err(_) => return true,
+    };
+
+    match (preferred.type_(), preferred.subtype()) {
+        (mime::STAR, _) => true,
+        (mime::TEXT, subtype) => subtype == mime::HTML || subtype == mime::STAR,
+        _ => false,
+    }
+}
+
+/// Replace the body of an error response with its status page, if there is one.
+async fn render_error_page<B: MessageBody>(
+    config: Rc<Config>,
+    context_builder: ContextBuilder,
+    res: ServiceResponse<B>,
+) -> Result<ServiceResponse<EitherBody<B>>, Error> {
+    if !accepts_html(res.request()) {
+        return Ok(match &config.not_found_body {
+            Some((content_type, body))
+                if res.status() == StatusCode::NOT_FOUND
+                    && res.response().body().size().is_eof() =>
+            {
+                let (req, mut response) = res.into_parts();
+                response
+                    .headers_mut()
+                    .insert(header::CONTENT_TYPE, HeaderValue::try_from(content_type)?);
+                response.headers_mut().remove(header::CONTENT_LENGTH);
+                let response = response.set_body(BoxBody::new(body.clone()));
+                ServiceResponse::new(req, response).map_into_right_body()
+            }
+            _ => res.map_into_left_body(),
+        });
+    }
+
+    let status = res.status();
+    let template = match config.template_prefix.as_str() {
+        "" => format!("{}.{}", status.as_u16(), config.extension),
+        prefix => format!("{}/{}.{}", prefix, status.as_u16(), config.extension),
+    };
+
+    let req = ServiceRequest::from_request(res.request().clone());
+    let tera = match (config.tera_lookup)(&req) {
+        Some(tera) if tera.get_template(&template).is_ok() => config.autoescaping(tera),
+        _ => return Ok(res.map_into_left_body()),
+    };
+
+    let context = match build_context(&config, &context_builder, &req, &tera, &template).await {
+        Ok(PageOutcome::Render(context)) => context,
+        Ok(_) => return Ok(res.map_into_left_body()),
+        Err(e) => {
+            error!(
+                "Failed to build context for error page: {}",
+                error_chain(&e)
+            );
+            return Ok(res.map_into_left_body());
+        }
+    };
+    let body = match render(&config, &tera, &template, &context) {
+        Ok(body) => body,
+        Err((failed, e)) => {
+            error!(
+                "Failed to render error page {:?}: {}",
+                config.display_name(&failed),
+                e
+            );
+            return Ok(res.map_into_left_body());
+        }
+    };
+
+    let mut page = HttpResponse::build(status);
+    for (name, value) in res.headers() {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            page.append_header((name.clone(), value.clone()));
+        }
+    }
+    if let Some(content_type) = config.content_type(&template) {
+        page.insert_header((header::CONTENT_TYPE, content_type));
+    }
+
+    let (req, _) = res.into_parts();
+    Ok(ServiceResponse::new(req, page.body(body)).map_into_right_body())
+}
+
+/// Render a matched template and build the page response. Requests are passed on with
+/// `fall_through` when the context gate asks for it, or answered with a 404 without one.
+#[cfg_attr(not(feature = "static-cache"), allow(unused_variables))]
+async fn render_page(
+    config: Rc<Config>,
+    context_builder: ContextBuilder,
+    tera: TeraHandle,
+    generation: u64,
+    template: String,
+    req: ServiceRequest,
+    fall_through: Option<FallThrough>,
+) -> PageResult {
+    if let Some(block) = &config.legal_block {
+        if (block.predicate)(req.request()) {
+            debug!("Request blocked for legal reasons.");
+            let status = StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS;
+            let response = status_page(
+                &config,
+                &context_builder,
+                &tera,
+                &req,
+                status,
+                &block.template,
+            )
+            .await;
+            return Ok(req.into_response(response));
+        }
+    }
+
+    #[cfg(any(feature = "brotli", feature = "gzip"))]
+    let encoding = compress::negotiate(&req);
+    let etag = config
+        .etag
+        .as_ref()
+        .and_then(|resolve| resolve(&template, req.request()));
+    // Each encoding of a page is a different representation, so it gets its own tag.
+    #[cfg(any(feature = "brotli", feature = "gzip"))]
+    let etag = match (etag, &encoding) {
+        (Some(tag), Some(encoding)) => Some(format!("{}-{}", tag, encoding)),
+        (etag, _) => etag,
+    };
+    let etag = etag
+        // Tags can't hold quotes or control characters, which `EntityTag` panics on.
+        .filter(|tag| {
+            tag.bytes()
+                .all(|b| b == 0x21 || (0x23..=0x7e).contains(&b) || b >= 0x80)
+        })
+        .map(EntityTag::new_strong);
+    if let Some(etag) = &etag {
+        let fresh = match IfNoneMatch::parse(&req) {
+            Ok(IfNoneMatch::Any) => true,
+            Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(etag)),
+            Err(_) => false,
+        };
+        if fresh {
+            debug!("ETag matched, skipping render.");
+            let response = HttpResponse::NotModified()
+                .insert_header(ETag(etag.clone()))
+                .finish();
+            return Ok(req.into_response(response));
+        }
+    }
+
+    #[cfg(feature = "static-cache")]
+    let is_static = config.static_pages.iter().any(|p| is_under(req.path(), p));
+    // Pages identifying the requester are rendered for every request and never cached.
+    #[cfg(feature = "static-cache")]
+    let personal = is_personal(&config, &req);
+    #[cfg(feature = "static-cache")]
+    let key = cache_key(&config, &template, req.path(), Some(&req));
+    #[cfg(feature = "static-cache")]
+    let lookup = is_static && !personal && !wants_fresh(&config, &req);
+    #[cfg(feature = "static-cache")]
+    let cached = lookup
+        .then(|| config.cache.get_aged(&key, generation))
+        .flatten()
+        .and_then(|(body, age)| match &config.swr {
+            Some(swr) if age > swr.max_age + swr.stale_window => None,
+            Some(swr) if age > swr.max_age => {
+                revalidate(
+                    &config,
+                    &context_builder,
+                    &tera,
+                    generation,
+                    &template,
+                    &key,
+                    &req,
+                );
+                Some(body)
+            }
+            _ => Some(body),
+        });
+    // Pages warmed before any request still need the post-render hook, which takes one.
+    #[cfg(feature = "static-cache")]
+    let cached = match (cached, &config.post_render) {
+        (None, Some(hook)) if lookup => match config.cache.take_warmed(&key, generation) {
+            Some(body) => match finish_body(&config, &template, body, Some(req.request())).await {
+                Ok(body) => {
+                    let body = Bytes::from(body);
+                    config.cache.insert(key.clone(), body.clone(), generation);
+                    Some(body)
+                }
+                Err(response) => return Ok(req.into_response(response)),
+            },
+            None => None,
+        },
+        (cached, _) => cached,
+    };
+    #[cfg(not(feature = "static-cache"))]
+    let cached: Option<Bytes> = None;
+
+    let (body, private, stale) = match cached {
+        Some(body) => {
+            debug_assert!(std::str::from_utf8(&body).is_ok());
+            (body, false, false)
+        }
+        #[cfg_attr(not(feature = "static-cache"), allow(unused_labels))]
+        None => 'render: {
+            // Held until the page is rendered, bounding concurrent context builds and renders.
+            let _permit = match render_permit(&config).await {
+                Ok(permit) => permit,
+                Err(response) => return Ok(req.into_response(response)),
+            };
+
+            let context = match build_context(&config, &context_builder, &req, &tera, &template)
+                .await
+            {
+                Ok(PageOutcome::Render(context)) => context,
+                Ok(PageOutcome::Unavailable { retry_after }) => {
+                    debug!(
+                        "Context builder declined to render, retry after {:?}.",
+                        retry_after
+                    );
+                    return Ok(req.into_response(unavailable(retry_after)));
+                }
+                Ok(PageOutcome::Status(status)) => {
+                    debug!("Context gate answered with {}.", status);
+                    return Ok(req.into_response(HttpResponse::build(status).finish()));
+                }
+                Ok(PageOutcome::FallThrough) => {
+                    debug!("Context gate passed the request through.");
+                    drop(_permit);
+                    return match fall_through {
+                        Some(fall_through) => fall_through(req).await,
+                        None => Ok(req.into_response(HttpResponse::NotFound().finish())),
+                    };
+                }
+                Err(e) => {
+                    #[cfg(feature = "static-cache")]
+                    if let Some(body) = config.stale.as_ref().and_then(|s| s.get(&key, generation))
+                    {
+                        warn!(
+                            "Failed to build context for {:?}, serving a stale render: {}",
+                            config.display_name(&template),
+                            error_chain(&e)
+                        );
+                        break 'render (body, false, true);
+                    }
+                    config.metrics.record_failure();
+                    return Ok(req.into_response(render_error(&config, &tera, &template, e)));
+                }
+            };
+            let private = is_private(&config, &context);
+            if let Some(key) = config
+                .htmx
+                .as_ref()
+                .and_then(|htmx| htmx.trigger_key.as_ref())
+                .filter(|_| req.headers().contains_key(HX_REQUEST))
+            {
+                if let Some(value) = context.get(key) {
+                    let trigger = match value {
+                        Value::String(event) => event.clone(),
+                        value => value.to_string(),
+                    };
+                    req.extensions_mut().insert(HxTrigger(trigger));
+                }
+            }
+
+            let rendered = render_checked(&config, &tera, &template, context, Some(req.request()));
+            let body = match rendered.await {
+                Ok(body) => body,
+                Err(response) => return Ok(req.into_response(response)),
+            };
+
+            // Tera renders to a `String`, so bodies are always valid UTF-8 and the
+            // `charset=utf-8` content types hold. Anything inspecting the bytes later (such
+            // as redirect directives) still checks rather than assuming, so it can't panic.
+            let body = Bytes::from(body);
+            #[cfg(feature = "static-cache")]
+            if !private && !personal {
+                if is_static {
+                    config.cache.insert(key.clone(), body.clone(), generation);
+                }
+                if let Some(stale) = &config.stale {
+                    stale.insert(key.clone(), body.clone(), generation);
+                }
+            }
+            (body, private, false)
+        }
+    };
+    config.metrics.record_render();
+
+    if config.template_redirects {
+        if let Some(location) = redirect_directive(&body) {
+            debug!("Template requested a redirect to {:?}", location);
+            let response = HttpResponse::Found()
+                .insert_header((header::LOCATION, location))
+                .finish();
+            return Ok(req.into_response(response));
+        }
+    }
+
+    if let Some(handler) = config.overrides.get(&template) {
+        debug!("Building the response with an override handler.");
+        let body = String::from_utf8_lossy(&body).into_owned();
+        let response = handler(req.request().clone(), body);
+        return Ok(req.into_response(response));
+    }
+
+    let mut response = HttpResponse::build(config.success_status);
+    if let Some(content_type) = config.content_type(&template) {
+        response.insert_header((header::CONTENT_TYPE, content_type));
+    }
+    if let Some(etag) = etag {
+        response.insert_header(ETag(etag));
+    }
+    if let Some(cache_control) = config.cache_control.get(&template) {
+        response.insert_header((header::CACHE_CONTROL, cache_control.as_str()));
+    }
+    #[cfg(feature = "static-cache")]
+    if let Some(swr) = config.swr.as_ref().filter(|_| is_static && !personal) {
+        let seconds = |d: Duration| u32::try_from(d.as_secs()).unwrap_or(u32::MAX);
+        response.insert_header(CacheControl(vec![
+            CacheDirective::MaxAge(seconds(swr.max_age)),
+            CacheDirective::Extension(
+                "stale-while-revalidate".to_string(),
+                Some(seconds(swr.stale_window).to_string()),
+            ),
+        ]));
+    }
+    if stale {
+        response.insert_header((header::WARNING, "110 - \"Response is Stale\""));
+    }
+    if private {
+        response.insert_header(CacheControl(vec![
+            CacheDirective::Private,
+            CacheDirective::NoStore,
+        ]));
+    }
+    if config.noindex.iter().any(|p| is_under(req.path(), p)) {
+        response.insert_header((HeaderName::from_static("x-robots-tag"), "noindex, nofollow"));
+    }
+    if let Some(filename) = config
+        .attachment
+        .as_ref()
+        .and_then(|resolve| resolve(&template, req.request()))
+    {
+        response.insert_header(ContentDisposition::attachment(filename));
+    }
+    if config.fragment_suffix.is_some() {
+        for name in &config.fragment_headers {
+            response.append_header((header::VARY, name.as_str()));
+        }
+    }
+    if !config.locales.is_empty() {
+        response.append_header((header::VARY, "accept-language"));
+    }
+    if config.device_variant.is_some() {
+        response.append_header((header::VARY, "user-agent"));
+    }
+    if config.nojs.is_some() || config.ab_variant.is_some() {
+        response.append_header((header::VARY, "cookie"));
+    }
+    #[cfg(feature = "csp")]
+    if config.csp_hashes && extension_of(&template) == Some(&config.extension) {
+        if let Ok(html) = std::str::from_utf8(&body) {
+            response.insert_header((header::CONTENT_SECURITY_POLICY, csp::policy(html)));
+        }
+    }
+    if let Some(htmx) = &config.htmx {
+        if req.headers().contains_key(HX_REQUEST) {
+            if htmx.push_url {
+                let location = match req.query_string() {
+                    "" => req.path().to_string(),
+                    query => format!("{}?{}", req.path(), query),
+                };
+                response.insert_header((HeaderName::from_static("hx-push-url"), location));
+            }
+            if let Some(HxTrigger(trigger)) = req.extensions().get::<HxTrigger>() {
+                if let Ok(trigger) = HeaderValue::from_str(trigger) {
+                    response.insert_header((HeaderName::from_static("hx-trigger"), trigger));
+                }
+            }
+        }
+    }
+    for asset in config.preload.get(&template).into_iter().flatten() {
+        response.append_header((header::LINK, preload_link(asset)));
+    }
+    if let Some(cookies) = &config.set_cookies {
+        let https = req.connection_info().scheme() == "https";
+        for mut cookie in cookies(req.request()) {
+            if cookie.same_site().is_none() {
+                cookie.set_same_site(SameSite::Lax);
+            }
+            if cookie.secure().is_none() && https {
+                cookie.set_secure(true);
+            }
+            response.cookie(cookie);
+        }
+    }
+
+    if config
+        .content_type(&template)
+        .is_some_and(|t| t.starts_with("text/event-stream"))
+    {
+        response.insert_header((HeaderName::from_static("x-accel-buffering"), "no"));
+        let events = stream::once(ready(Ok::<_, Error>(body)));
+        return Ok(req.into_response(response.streaming(events)));
+    }
+
+    // Encoded pages are always sent whole, even for static pages asked for a range.
+    #[cfg(any(feature = "brotli", feature = "gzip"))]
+    {
+        response.append_header((header::VARY, "accept-encoding"));
+        if let Some(encoding) = encoding {
+            let name = encoding.to_string();
+            #[cfg(feature = "static-cache")]
+            let cached = config.cache.get_encoded(&key, &body, &name, generation);
+            #[cfg(not(feature = "static-cache"))]
+            let cached: Option<Bytes> = None;
+            let encoded = match cached {
+                Some(encoded) => Ok(encoded),
+                None => {
+                    #[cfg(feature = "test-util")]
+                    config.metrics.record_compression();
+                    let encoded = compress::encode(&encoding, &body);
+                    // Only kept if `body` is the cached body of a static page.
+                    #[cfg(feature = "static-cache")]
+                    if let Ok(encoded) = &encoded {
+                        let (name, encoded) = (name.clone(), encoded.clone());
+                        config
+                            .cache
+                            .insert_encoded(&key, &body, name, encoded, generation);
+                    }
+                    encoded
+                }
+            };
+            match encoded {
+                Ok(encoded) => {
+                    response.insert_header((header::CONTENT_ENCODING, name));
+                    return Ok(req.into_response(response.body(encoded)));
+                }
+                Err(e) => error!("Failed to compress page with {}: {}", encoding, e),
+            }
+        }
+    }
+
+    #[cfg(feature = "static-cache")]
+    if is_static {
+        let response = cache::ranged_response(&req, response, body);
+        return Ok(req.into_response(response));
+    }
+
+    #[cfg(feature = "lab")]
+    if config.lab_html && extension_of(&template) == Some(&config.extension) {
+        let response = lab::html_response(req.request(), response, body);
+        return Ok(req.into_response(response));
+    }
+
+    // The body is sized, so actix-web sends its `Content-Length`, measured after any
+    // re-encoding by the `Compress` middleware.
+    Ok(req.into_response(response.body(body)))
+}
+
+/// Returns true if the middleware would put values identifying the requester into the
+/// context: the principal, a CSRF token, a request ID or pending flash messages.
+#[cfg(feature = "static-cache")]
+fn is_personal(config: &Config, req: &ServiceRequest) -> bool {
+    let user = config.principal.as_ref().is_some_and(|p| p(req).is_some());
+    let csrf = config.csrf_token.as_ref().is_some_and(|t| t(req).is_some());
+    let request_id = req.extensions().contains::<RequestId>();
+    #[cfg(feature = "actix-session")]
+    let flash = config.flash
+        && req
+            .get_session()
+            .get::<Value>(TeraPage::FLASH_KEY)
+            .is_ok_and(|flash| flash.is_some());
+    #[cfg(not(feature = "actix-session"))]
+    let flash = false;
+    user || csrf || request_id || flash
+}
+
+/// The key a page is cached under: its template and path, along with the locale, device
+/// class and A/B variant injected into its context, so pages sharing a template are cached
+/// apart. Pages warmed without a request get the default locale and neither of the others.
+#[cfg(feature = "static-cache")]
+fn cache_key(config: &Config, template: &str, path: &str, req: Option<&ServiceRequest>) -> String {
+    let locale = match req {
+        Some(req) => resolve_locale(req, &config.locales),
+        None => config.locales.first(),
+    };
+    let extensions = req.map(|req| req.extensions());
+    let extensions = extensions.as_ref();
+    let device = extensions.and_then(|e| e.get::<DeviceClass>().map(|d| d.0.clone()));
+    let variant = extensions.and_then(|e| e.get::<AbVariant>().map(|v| v.0.clone()));
+
+    [
+        template,
+        path,
+        locale.map_or("", String::as_str),
+        device.as_deref().unwrap_or_default(),
+        variant.as_deref().unwrap_or_default(),
+    ]
+    .join("\n")
+}
+
+/// Re-render a stale static page in the background and replace its cached body, unless
+/// that is already underway.
+#[cfg(feature = "static-cache")]
+fn revalidate(
+    config: &Rc<Config>,
+    context_builder: &ContextBuilder,
+    tera: &TeraHandle,
+    generation: u64,
+    template: &str,
+    key: &str,
+    req: &ServiceRequest,
+) {
+    let Some(swr) = &config.swr else { return };
+    if !swr.revalidating.borrow_mut().insert(key.to_string()) {
+        return;
+    }
+    debug!(
+        "Serving stale {:?} while it is re-rendered.",
+        config.display_name(template)
+    );
+
+    let config = config.clone();
+    let context_builder = context_builder.clone();
+    let tera = tera.clone();
+    let template = template.to_string();
+    let key = key.to_string();
+    let req = ServiceRequest::from_request(req.request().clone());
+    let revalidating = swr.revalidating.clone();
+    rt::spawn(async move {
+        let rendered = async {
+            let _permit = render_permit(&config).await.ok()?;
+            let context =
+                match build_context(&config, &context_builder, &req, &tera, &template).await {
+                    Ok(PageOutcome::Render(context)) => context,
+                    Ok(_) => {
+                        debug!("Context builder declined to re-render, keeping the stale page.");
+                        return None;
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to build context to re-render {:?}: {}",
+                            config.display_name(&template),
+                            error_chain(&e)
+                        );
+                        return None;
+                    }
+                };
+            if is_private(&config, &context) || is_personal(&config, &req) {
+                debug!("Re-rendered page is private, keeping it out of the cache.");
+                return None;
+            }
+            let rendered = render_checked(&config, &tera, &template, context, Some(req.request()));
+            rendered.await.ok()
+        };
+        if let Some(body) = rendered.await {
+            config
+                .cache
+                .insert(key.clone(), Bytes::from(body), generation);
+        }
+        revalidating.borrow_mut().remove(&key);
+    });
+}
+
+/// A permit to build and render a page under the
+/// [`max_concurrent_renders`](TeraPage::max_concurrent_renders) limit, or the response to
+/// answer with when the limit is reached and the middleware fails fast.
+async fn render_permit(config: &Config) -> Result<Option<OwnedSemaphorePermit>, HttpResponse> {
+    match &config.render_limit {
+        Some(limit) if config.fail_fast => match limit.clone().try_acquire_owned() {
+            Ok(permit) => Ok(Some(permit)),
+            Err(_) => {
+                debug!("Render limit reached, rejecting request.");
+                Err(config
+                    .static_error(StatusCode::SERVICE_UNAVAILABLE)
+                    .unwrap_or_else(|| HttpResponse::ServiceUnavailable().finish()))
+            }
+        },
+        Some(limit) => Ok(limit.clone().acquire_owned().await.ok()),
+        None => Ok(None),
+    }
+}
+
+/// Render a page from its built context, the same way for responses, background re-renders
+/// and warmed pages: on the blocking pool or within the render timeout if configured, then
+/// through [`finish_body`]. Failures are logged and counted, and returned as the response
+/// to answer with.
+async fn render_checked(
+    config: &Config,
+    tera: &TeraHandle,
+    template: &str,
+    context: Context,
+    req: Option<&HttpRequest>,
+) -> Result<String, HttpResponse> {
+    let rendered = match config.render_timeout {
+        Some(timeout) => {
+            let render = render_blocking(config, tera, template, context);
+            match rt::time::timeout(timeout, render).await {
+                Ok(rendered) => rendered,
+                Err(_) => {
+                    error!(
+                        "Rendering template {:?} took longer than {:?}.",
+                        config.display_name(template),
+                        timeout
+                    );
+                    config.metrics.record_failure();
+                    return Err(config
+                        .static_error(StatusCode::SERVICE_UNAVAILABLE)
+                        .unwrap_or_else(|| HttpResponse::ServiceUnavailable().finish()));
+                }
+            }
+        }
+        None if config.render_on_blocking => render_blocking(config, tera, template, context).await,
+        None => render(config, tera, template, &context),
+    };
+    match rendered {
+        Ok(body) => finish_body(config, template, body, req).await,
+        Err((failed, e)) => {
+            config.metrics.record_failure();
+            Err(render_error(config, tera, &failed, e))
+        }
+    }
+}
+
+/// Pass a rendered body through the post-render hook, when there is a request to give it,
+/// and check it against [`max_body_size`](TeraPage::max_body_size).
+async fn finish_body(
+    config: &Config,
+    template: &str,
+    mut body: String,
+    req: Option<&HttpRequest>,
+) -> Result<String, HttpResponse> {
+    if let (Some(hook), Some(req)) = (&config.post_render, req) {
+        body = hook(body, req).await;
+    }
+
+    if let Some(limit) = config.max_body_size {
+        if body.len() > limit {
+            error!(
+                "Rendered template {:?} is {} bytes, exceeding the limit of {} bytes.",
+                config.display_name(template),
+                body.len(),
+                limit
+            );
+            config.metrics.record_failure();
+            return Err(config
+                .static_error(StatusCode::INTERNAL_SERVER_ERROR)
+                .unwrap_or_else(|| HttpResponse::InternalServerError().finish()));
+        }
+    }
+    Ok(body)
+}
+
+/// Returns true if the context marks the page private, under
+/// [`auto_private`](TeraPage::auto_private).
+fn is_private(config: &Config, context: &Context) -> bool {
+    config.auto_private && context.get(TeraPage::PRIVATE_KEY) == Some(&Value::Bool(true))
+}
+
+/// Settle on the ID of a request, storing it in the request extensions, and return it as a
+/// header value if it can be one.
+fn assign_request_id(req: &ServiceRequest, header: &HeaderName) -> Option<HeaderValue> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let existing = req.extensions().get::<RequestId>().cloned();
+    let id = existing
+        .or_else(|| {
+            let value = req.headers().get(header)?.to_str().ok()?;
+            Some(RequestId(value.to_string()))
+        })
+        .unwrap_or_else(|| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u64(NEXT.fetch_add(1, Ordering::Relaxed));
+            RequestId(format!("{:016x}", hasher.finish()))
+        });
+
+    let value = HeaderValue::from_str(&id.0).ok();
+    req.extensions_mut().insert(id);
+    value
+}
+
+/// Returns true if the client asked for a fresh page and the middleware honors it for them.
+fn wants_fresh(config: &Config, req: &ServiceRequest) -> bool {
+    config
+        .honor_no_cache
+        .as_ref()
+        .is_some_and(|trusted| trusted(req.request()))
+        && CacheControl::parse(req).is_ok_and(|cc| cc.contains(&CacheDirective::NoCache))
+}
+
+/// The location to redirect a request to `target`, with the request's query string unless
+/// the middleware is configured not to preserve it.
+fn redirect_location(config: &Config, req: &ServiceRequest, target: &str) -> String {
+    match req.query_string() {
+        query if !config.preserve_query || query.is_empty() => target.to_string(),
+        query => format!("{}?{}", target, query),
+    }
+}
+
+/// Render a matched template, along with any composition fragments around it. On failure,
+/// returns the name of the template that failed alongside the error.
+fn render(
+    config: &Config,
+    tera: &Tera,
+    template: &str,
+    context: &Context,
+) -> Result<String, (String, tera::Error)> {
+    render_with(&config.render_options(), tera, template, context)
+}
+
+/// Render a page with [`render`] on the blocking thread pool.
+async fn render_blocking(
+    config: &Config,
+    tera: &TeraHandle,
+    template: &str,
+    context: Context,
+) -> Result<String, (String, tera::Error)> {
+    let options = config.render_options();
+    let tera = tera.clone();
+    let name = template.to_string();
+    web::block(move || render_with(&options, &tera, &name, &context))
+        .await
+        .unwrap_or_else(|e| Err((template.to_string(), tera::Error::msg(e))))
+}
+
+/// [`render`], with the options it needs taken out of the config.
+fn render_with(
+    config: &RenderOptions,
+    tera: &Tera,
+    template: &str,
+    context: &Context,
+) -> Result<String, (String, tera::Error)> {
+    let render_one = |name: &str| {
+        tera.render(name, context)
+            .map_err(|e| (name.to_string(), e))
+    };
+
+    let finish = |body: String| {
+        if config.minify && extension_of(template) == Some(&config.extension) {
+            minify::minify(&body)
+        } else {
+            body
+        }
+    };
+
+    let page = render_one(template)?;
+    #[cfg(feature = "test-util")]
+    config.metrics.record_template_render();
+    if config.compose.is_empty() {
+        return Ok(finish(page));
+    }
+
+    let mut body = String::new();
+    let mut page = Some(page);
+    for fragment in &config.compose {
+        if fragment == TeraPage::COMPOSED_PAGE {
+            body.push_str(&page.take().unwrap_or_default());
+        } else {
+            body.push_str(&render_one(fragment)?);
+        }
+    }
+    if let Some(page) = page {
+        body.push_str(&page);
+    }
+
+    Ok(finish(body))
+}
+
+/// Log a render failure and build the 500 response for it.
+fn render_error(config: &Config, tera: &Tera, template: &str, e: tera::Error) -> HttpResponse {
+    let name = config.display_name(template);
+    let mut message = error_chain(&e);
+    if config.redact_template_paths {
+        // The chain names every template involved, such as parents and includes. Longer
+        // names go first so that names ending another one (`base.html`) don't split it.
+        let mut names = tera
+            .get_template_names()
+            .chain([template])
+            .filter(|n| message.contains(n))
+            .collect::<Vec<_>>();
+        names.sort_by_key(|n| std::cmp::Reverse(n.len()));
+        names.dedup();
+        for n in names {
+            message = message.replace(n, &config.display_name(n));
+        }
+    }
+
+    match &config.logger {
+        Some(logger) => logger.render_error(&name, &message),
+        None => error!("Failed to render template {:?}: {}", name, message),
+    }
+    config
+        .static_error(StatusCode::INTERNAL_SERVER_ERROR)
+        .unwrap_or_else(|| {
+            HttpResponse::InternalServerError().body(format!("Failed to render template {}", name))
+        })
+}
+
+/// An error message followed by those of its sources, which is where Tera puts the details.
+fn error_chain(e: &dyn std::error::Error) -> String {
+    let mut message = e.to_string();
+    let mut source = e.source();
+    while let Some(e) = source {
+        message = format!("{}: {}", message, e);
+        source = e.source();
+    }
+    message
+}
+
+/// Answer with `status`, rendering `template` if Tera has it or the status's reason phrase
+/// otherwise.
+async fn status_page(
+    config: &Config,
+    context_builder: &ContextBuilder,
+    tera: &Tera,
+    req: &ServiceRequest,
+    status: StatusCode,
+    template: &str,
+) -> HttpResponse {
+    if tera.get_template(template).is_err() {
+        return HttpResponse::build(status).body(status.canonical_reason().unwrap_or_default());
+    }
+
+    let context = match build_context(config, context_builder, req, tera, template).await {
+        Ok(PageOutcome::Render(context)) => context,
+        Ok(PageOutcome::Unavailable { retry_after }) => return unavailable(retry_after),
+        Ok(PageOutcome::Status(status)) => return HttpResponse::build(status).finish(),
+        Ok(PageOutcome::FallThrough) => {
+            return HttpResponse::build(status).body(status.canonical_reason().unwrap_or_default())
+        }
+        Err(e) => return render_error(config, tera, template, e),
+    };
+    match render(config, tera, template, &context) {
+        Ok(body) => HttpResponse::build(status)
+            .content_type("text/html; charset=utf-8")
+            .body(body),
+        Err((failed, e)) => render_error(config, tera, &failed, e),
+    }
+}
+
+/// Answer a request the context builder declined with [`PageOutcome::Unavailable`].
+fn unavailable(retry_after: Duration) -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .insert_header((header::RETRY_AFTER, retry_after.as_secs().to_string()))
+        .finish()
+}
+
+/// Run the context builder and merge in the shared context and request-derived values.
+async fn build_context(
+    config: &Config,
+    context_builder: &ContextBuilder,
+    req: &ServiceRequest,
+    tera: &Tera,
+    template: &str,
+) -> tera::Result<PageOutcome> {
+    let built = match context_builder(req.request().clone()).await? {
+        PageOutcome::Render(context) => context,
+        unavailable => return Ok(unavailable),
+    };
+    let mut context = config.globals.clone();
+    match &config.shared_context {
+        Some(shared) if wants_fresh(config, req) => {
+            debug!("Rebuilding the shared context for a no-cache request.");
+            let fresh = (shared.refresh)().await;
+            shared.current.store(Arc::new(fresh.clone()));
+            context.extend(fresh);
+        }
+        Some(shared) => context.extend(Context::clone(&shared.current.load())),
+        None => {}
+    }
+    context.extend(built);
+
+    let keys = &config.keys;
+    if let Some(locale) = resolve_locale(req, &config.locales) {
+        insert_missing(&mut context, &keys.locale, locale);
+    }
+    if let Some(user) = config.principal.as_ref().and_then(|p| p(req)) {
+        insert_missing(&mut context, &keys.user, &user);
+    }
+    if let Some(token) = config.csrf_token.as_ref().and_then(|t| t(req)) {
+        insert_missing(&mut context, &keys.csrf_token, &token);
+    }
+    #[cfg(feature = "actix-session")]
+    if config.flash && !context.contains_key(&keys.flash) {
+        match req.get_session().remove_as::<Value>(TeraPage::FLASH_KEY) {
+            Some(Ok(flash)) => context.insert(&keys.flash, &flash),
+            Some(Err(e)) => warn!("Discarding unreadable flash messages: {}", e),
+            None => {}
+        }
+    }
+    if let Some(AbVariant(variant)) = req.extensions().get::<AbVariant>() {
+        insert_missing(&mut context, &keys.ab_variant, variant);
+    }
+    if let Some(DeviceClass(device)) = req.extensions().get::<DeviceClass>() {
+        insert_missing(&mut context, &keys.device, device);
+    }
+    if let Some(slash) = config.index_canonical {
+        if !context.contains_key(&keys.canonical) {
+            let page = template.rsplit('/').next().unwrap_or(template);
+            let is_index = page.starts_with(&format!("{}.", config.index_name));
+            let matched = req
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|MatchedPath(path)| collapse_slashes(path));
+            let matched = matched.unwrap_or_else(|| collapse_slashes(req.path()));
+            let path = matched.trim_end_matches('/');
+            let canonical = if path.is_empty() || is_index && slash == TrailingSlash::Always {
+                format!("{}/", path)
+            } else {
+                path.to_string()
+            };
+            context.insert(&keys.canonical, &canonical);
+        }
+    }
+    if let Some(info) = &config.build_info {
+        insert_missing(&mut context, &keys.build, info);
+    }
+    if config.inject_path {
+        let normalized = collapse_slashes(req.path());
+        let normalized = match normalized.trim_end_matches('/') {
+            "" => "/",
+            trimmed => trimmed,
+        };
+        insert_missing(&mut context, &keys.path, req.path());
+        insert_missing(&mut context, &keys.normalized_path, normalized);
+    }
+    if let Some(RequestId(id)) = req.extensions().get::<RequestId>() {
+        insert_missing(&mut context, &keys.request_id, id);
+    }
+    if let Some(Captures(captures)) = req.extensions().get::<Captures>() {
+        insert_missing(&mut context, &keys.captures, captures);
+    }
+    if let Some(Autoindex(entries)) = req.extensions().get::<Autoindex>() {
+        insert_missing(&mut context, &keys.entries, entries);
+    }
+    if config.inject_template_source && !context.contains_key(&keys.template_source) {
+        let path = tera
+            .get_template(template)
+            .ok()
+            .and_then(|t| t.path.clone());
+        let mut source = tera::Map::new();
+        source.insert("name".to_string(), template.into());
+        source.insert("path".to_string(), path.into());
+        context.insert(&keys.template_source, &source);
+    }
+
+    Ok(match &config.context_gate {
+        Some(gate) => gate(&context, template),
+        None => PageOutcome::Render(context),
+    })
+}
+
+/// Insert `value` under `key`, unless the context builder already set it.
+fn insert_missing<T: Serialize + ?Sized>(context: &mut Context, key: &str, value: &T) {
+    if !context.contains_key(key) {
+        context.insert(key, value);
+    }
+}
+
+/// Render the pages for `paths` with `context` over the globals into the static page cache.
+#[cfg(feature = "static-cache")]
+async fn warm_cache(
+    config: &Config,
+    tera: TeraHandle,
+    context: Context,
+    paths: &[String],
+) -> Result<(), HttpResponse> {
+    let generation = config.generation();
+    let mut globals = config.globals.clone();
+    globals.extend(context);
+    let tera = config.autoescaping(tera);
+    for path in paths {
+        let template = config
+            .candidates(&config.template_prefix, path, &[])
+            .into_iter()
+            .flatten()
+            .find(|c| tera.get_template(c).is_ok());
+        let template = match template {
+            Some(template) => template,
+            None => {
+                debug!("No template to warm for path {:?}.", path);
+                continue;
+            }
+        };
+
+        let body = render_checked(config, &tera, &template, globals.clone(), None).await?;
+        let key = cache_key(config, &template, path, None);
+        match &config.post_render {
+            Some(_) => config.cache.insert_warmed(key, body, generation),
+            None => config.cache.insert(key, Bytes::from(body), generation),
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns true if the request is a protocol upgrade handshake (e.g. WebSocket),
+/// which should always be left to the inner service.
+fn is_upgrade(req: &ServiceRequest) -> bool {
+    req.head().upgrade() || req.headers().contains_key(header::UPGRADE)
+}
+
+/// The location of a `REDIRECT: <location>` directive on the first line of a rendered body.
+fn redirect_directive(body: &[u8]) -> Option<&str> {
+    let body = body.trim_ascii_start();
+    let line = body.split(|b| *b == b'\n').next()?;
+    let location = std::str::from_utf8(line)
+        .ok()?
+        .strip_prefix("REDIRECT:")?
+        .trim();
+
+    (!location.is_empty()).then_some(location)
+}
+
+/// The host a request was made to, without the port.
+fn request_host(req: &ServiceRequest) -> Option<&str> {
+    let host = match req.headers().get(header::HOST) {
+        Some(host) => host.to_str().ok()?,
+        None => req.uri().host()?,
+    };
+
+    // Keep bracketed IPv6 addresses intact while stripping a trailing port.
+    match host.rfind(':') {
+        Some(i) if !host[i..].contains(']') => Some(&host[..i]),
+        _ => Some(host),
+    }
+}
+
+/// The value of a `Link` header preloading `asset`, with `as` set when the kind of asset
+/// can be told from its extension.
+fn preload_link(asset: &str) -> String {
+    let kind = match extension_of(asset.split(['?', '#']).next().unwrap_or(asset)) {
+        Some("css") => Some("style"),
+        Some("js" | "mjs") => Some("script"),
+        Some("woff" | "woff2" | "ttf" | "otf") => Some("font"),
+        Some("png" | "jpg" | "jpeg" | "gif" | "webp" | "avif" | "svg") => Some("image"),
+        _ => None,
+    };
+
+    match kind {
+        Some("font") => format!("<{}>; rel=preload; as=font; crossorigin", asset),
+        Some(kind) => format!("<{}>; rel=preload; as={}", asset, kind),
+        None => format!("<{}>; rel=preload", asset),
+    }
+}
+
+/// URLs of the pages directly beneath the directory at `path`: page templates in it, and
+/// subdirectories containing any template. Sorted, without duplicates.
+fn autoindex_entries(templates: &[&str], prefix: &str, path: &str, extension: &str) -> Vec<String> {
+    let dir = path.trim_matches('/');
+    let stem = match (prefix.is_empty(), dir.is_empty()) {
+        (true, _) => dir.to_string(),
+        (false, true) => prefix.to_string(),
+        (false, false) => format!("{}/{}", prefix, dir),
+    };
+    let base = if dir.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{}/", dir)
+    };
+
+    let mut entries = templates
+        .iter()
+        .filter_map(|name| match stem.as_str() {
+            "" => Some(*name),
+            stem => name.strip_prefix(stem)?.strip_prefix('/'),
+        })
+        .filter_map(|rest| match rest.split_once('/') {
+            Some((child, _)) => Some(format!("{}{}/", base, child)),
+            None => rest
+                .strip_suffix(&format!(".{}", extension))
+                .filter(|page| !page.contains('.'))
+                .map(|page| format!("{}{}", base, page)),
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+    entries.dedup();
+    entries
+}
+
+/// Match `text` against `pattern`, in which `*` stands for any run of characters.
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = text.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// The extension of the last segment of a path or template name, if any.
+fn extension_of(path: &str) -> Option<&str> {
+    let name = path.rsplit('/').next()?;
+    match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => Some(extension),
+        _ => None,
+    }
+}
+
+/// `path` with every run of slashes replaced by a single one.
+fn collapse_slashes(path: &str) -> String {
+    let mut collapsed = String::with_capacity(path.len());
+    for c in path.chars() {
+        if c != '/' || !collapsed.ends_with('/') {
+            collapsed.push(c);
+        }
+    }
+    collapsed
+}
+
+/// Returns true if `path` is `base` or a path beneath it, ignoring trailing slashes.
+fn is_under(path: &str, base: &str) -> bool {
+    let path = path.trim_end_matches('/');
+    let base = base.trim_end_matches('/');
+    match path.strip_prefix(base) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Pick the supported locale that best matches the request's `Accept-Language` header,
+/// comparing full tags first and then primary languages (so `fr-CA` matches `fr`).
+fn resolve_locale<'a>(req: &ServiceRequest, supported: &'a [String]) -> Option<&'a String> {
+    let ranked = AcceptLanguage::parse(req)
+        .map(|header| header.ranked())
+        .unwrap_or_default();
+
+    ranked
+        .iter()
+        .find_map(|preference| match preference {
+            Preference::Any => supported.first(),
+            Preference::Specific(tag) => supported
+                .iter()
+                .find(|l| l.eq_ignore_ascii_case(tag.as_str()))
+                .or_else(|| {
+                    supported.iter().find(|l| {
+                        let primary = l.split('-').next().unwrap_or_default();
+                        primary.eq_ignore_ascii_case(tag.primary_language())
+                    })
+                }),
+        })
+        .or_else(|| supported.first())
 }
@@ -0,0 +1,105 @@
+//! Counters describing what the middleware did with the requests it saw.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+#[derive(Default)]
+struct Counters {
+    requests: AtomicU64,
+    matched: AtomicU64,
+    rendered: AtomicU64,
+    failed: AtomicU64,
+    fallthroughs: AtomicU64,
+    #[cfg(feature = "test-util")]
+    template_renders: AtomicU64,
+    #[cfg(feature = "test-util")]
+    compressions: AtomicU64,
+}
+
+/// Handle to the middleware's counters. Clones share the same counters, so a single handle
+/// can be passed to every worker with [`TeraPage::with_metrics`](crate::TeraPage::with_metrics)
+/// and exported from elsewhere.
+#[derive(Clone, Default)]
+pub struct TeraPageMetrics {
+    counters: Arc<Counters>,
+}
+
+impl TeraPageMetrics {
+    /// Create a new set of counters, all starting at zero.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests seen by the middleware.
+    pub fn requests(&self) -> u64 {
+        self.counters.requests.load(Ordering::Relaxed)
+    }
+
+    /// Requests that matched a template.
+    pub fn matched(&self) -> u64 {
+        self.counters.matched.load(Ordering::Relaxed)
+    }
+
+    /// Pages served successfully.
+    pub fn rendered(&self) -> u64 {
+        self.counters.rendered.load(Ordering::Relaxed)
+    }
+
+    /// Pages that failed to render.
+    pub fn failed(&self) -> u64 {
+        self.counters.failed.load(Ordering::Relaxed)
+    }
+
+    /// Requests passed through to the inner service.
+    pub fn fallthroughs(&self) -> u64 {
+        self.counters.fallthroughs.load(Ordering::Relaxed)
+    }
+
+    /// Times a page was rendered by Tera, as opposed to served from a cache. Only available
+    /// with the `test-util` feature, for asserting on caching behavior in tests.
+    #[cfg(feature = "test-util")]
+    pub fn template_renders(&self) -> u64 {
+        self.counters.template_renders.load(Ordering::Relaxed)
+    }
+
+    /// Times a page was compressed, as opposed to sent with a cached encoding. Only
+    /// available with the `test-util` feature.
+    #[cfg(feature = "test-util")]
+    pub fn compressions(&self) -> u64 {
+        self.counters.compressions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_request(&self) {
+        self.counters.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_match(&self) {
+        self.counters.matched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_render(&self) {
+        self.counters.rendered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_failure(&self) {
+        self.counters.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_fallthrough(&self) {
+        self.counters.fallthroughs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "test-util")]
+    pub(crate) fn record_template_render(&self) {
+        self.counters
+            .template_renders
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(all(feature = "test-util", any(feature = "brotli", feature = "gzip")))]
+    pub(crate) fn record_compression(&self) {
+        self.counters.compressions.fetch_add(1, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,60 @@
+//! Light minification of rendered HTML.
+
+/// Elements whose content is copied through untouched.
+const PRESERVED: &[&str] = &["pre", "textarea", "script", "style"];
+
+/// Strip comments and collapse runs of whitespace outside of whitespace-sensitive elements.
+/// A run containing a line break becomes a single line break, any other run a single space.
+pub(crate) fn minify(html: &str) -> String {
+    let mut minified = String::with_capacity(html.len());
+    let mut rest = html;
+
+    while let Some(c) = rest.chars().next() {
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            match comment.find("-->") {
+                Some(end) => rest = &comment[end + 3..],
+                None => {
+                    minified.push_str(rest);
+                    break;
+                }
+            }
+        } else if let Some(element) = preserved_element(rest) {
+            let close = format!("</{}", element);
+            let end = rest
+                .to_ascii_lowercase()
+                .find(&close)
+                .map_or(rest.len(), |i| i + close.len());
+            minified.push_str(&rest[..end]);
+            rest = &rest[end..];
+        } else if c.is_ascii_whitespace() {
+            let trimmed = rest.trim_start_matches(|c: char| c.is_ascii_whitespace());
+            let line_break = rest[..rest.len() - trimmed.len()].contains('\n');
+            // Runs separated only by a stripped comment are merged.
+            match minified.chars().last() {
+                Some(' ') if line_break => {
+                    minified.pop();
+                    minified.push('\n');
+                }
+                Some(' ' | '\n') => {}
+                _ => minified.push(if line_break { '\n' } else { ' ' }),
+            }
+            rest = trimmed;
+        } else {
+            minified.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    minified
+}
+
+/// The name of the preserved element opened at the start of `html`, if any.
+fn preserved_element(html: &str) -> Option<&'static str> {
+    let tag = html.strip_prefix('<')?;
+    PRESERVED.iter().copied().find(|name| {
+        tag.get(..name.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(name))
+            && tag[name.len()..]
+                .starts_with(|c: char| c == '>' || c == '/' || c.is_ascii_whitespace())
+    })
+}
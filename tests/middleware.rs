@@ -0,0 +1,3107 @@
+//! Tests of the middleware, serving templates added to `Tera` in memory.
+
+use actix_http::Request;
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    env, fs,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "regex")]
+use actix_tera_page::Regex;
+use actix_tera_page::{
+    build_info, Ambiguity, ArcSwap, BuildInfo, CandidateTransform, ConfigError, ContextProvider,
+    HtmxConfig, InjectionKeys, IntoContextValue, MatchStrategy, PageOutcome, PrefixPath, RequestId,
+    TeraPage, TeraPageLogger, TeraPageSend, TrailingSlash,
+};
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{fn_service, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header::HeaderMap, Method, StatusCode},
+    middleware::Compress,
+    rt,
+    test::{self, TestRequest},
+    web::{self, Bytes, Data},
+    App, Error, HttpMessage, HttpRequest, HttpResponse,
+};
+#[cfg(feature = "csp")]
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::{future::LocalBoxFuture, join};
+#[cfg(feature = "csp")]
+use sha2::{Digest, Sha256};
+use tera::{Context, Tera, Value};
+
+/// Body of the inner service's responses, telling requests that fell through apart.
+const INNER: &str = "inner service";
+
+/// Held by tests setting environment variables, which are shared by the whole process.
+static ENV: Mutex<()> = Mutex::new(());
+
+/// A response, with its body read.
+struct Page {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Page {
+    fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).unwrap()
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|v| v.to_str().unwrap())
+    }
+
+    /// Every value of a header that may be sent more than once, such as `Vary`.
+    fn header_values(&self, name: &str) -> Vec<&str> {
+        self.headers
+            .get_all(name)
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+}
+
+/// A `Tera` holding the given templates.
+fn tera(templates: &[(&str, &str)]) -> Tera {
+    let mut tera = Tera::default();
+    tera.add_raw_templates(templates.to_vec()).unwrap();
+    tera
+}
+
+/// An app serving `tera` through `page`, in front of an inner service answering every
+/// request with a 404 and [`INNER`].
+async fn app(
+    page: TeraPage,
+    tera: Tera,
+) -> impl Service<Request, Response = ServiceResponse, Error = Error> {
+    test::init_service(
+        App::new()
+            .app_data(Data::new(tera))
+            .wrap(page)
+            .default_service(web::to(|| async { HttpResponse::NotFound().body(INNER) })),
+    )
+    .await
+}
+
+async fn call<B: MessageBody>(
+    app: &impl Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    req: TestRequest,
+) -> Page {
+    let res = test::call_service(app, req.to_request()).await;
+    let status = res.status();
+    let headers = res.headers().clone();
+    let body = test::read_body(res).await;
+    Page {
+        status,
+        headers,
+        body,
+    }
+}
+
+async fn get<B: MessageBody>(
+    app: &impl Service<Request, Response = ServiceResponse<B>, Error = Error>,
+    uri: &str,
+) -> Page {
+    call(app, TestRequest::get().uri(uri)).await
+}
+
+#[actix_web::test]
+async fn upgrade_requests_fall_through() {
+    let tera = tera(&[("pages/chat.html", "chat page")]);
+    let app = app(TeraPage::static_only("pages"), tera).await;
+
+    let upgrade = TestRequest::get()
+        .uri("/chat")
+        .insert_header(("connection", "upgrade"))
+        .insert_header(("upgrade", "websocket"));
+    let res = call(&app, upgrade).await;
+    assert_eq!(res.status, StatusCode::NOT_FOUND);
+    assert_eq!(res.text(), INNER);
+
+    let res = get(&app, "/chat").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.text(), "chat page");
+}
+
+#[actix_web::test]
+async fn fragment_requests_get_the_fragment_template() {
+    let tera = tera(&[
+        ("pages/about.html", "full page"),
+        ("pages/about.fragment.html", "fragment"),
+    ]);
+    let app = app(
+        TeraPage::static_only("pages").fragment_suffix("fragment"),
+        tera,
+    )
+    .await;
+
+    let htmx = TestRequest::get()
+        .uri("/about")
+        .insert_header(("hx-request", "true"));
+    let res = call(&app, htmx).await;
+    assert_eq!(res.text(), "fragment");
+
+    let res = get(&app, "/about").await;
+    assert_eq!(res.text(), "full page");
+    let vary = res.header_values("vary");
+    assert!(vary.contains(&"hx-request") && vary.contains(&"x-requested-with"));
+}
+
+#[actix_web::test]
+async fn locale_is_resolved_from_accept_language() {
+    let tera = tera(&[("pages/index.html", "{{ locale }}")]);
+    let app = app(TeraPage::static_only("pages").locales(&["en", "fr"]), tera).await;
+
+    let french = TestRequest::get()
+        .uri("/")
+        .insert_header(("accept-language", "fr-CA, fr;q=0.9"));
+    let res = call(&app, french).await;
+    assert_eq!(res.text(), "fr");
+    assert!(res.header_values("vary").contains(&"accept-language"));
+
+    assert_eq!(get(&app, "/").await.text(), "en");
+}
+
+#[actix_web::test]
+async fn oversized_pages_are_refused() {
+    let tera = tera(&[
+        (
+            "pages/big.html",
+            "{% for i in range(end=100) %}x{% endfor %}",
+        ),
+        ("pages/small.html", "small"),
+    ]);
+    let app = app(TeraPage::static_only("pages").max_body_size(10), tera).await;
+
+    let res = get(&app, "/big").await;
+    assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(!res.text().contains('x'));
+
+    assert_eq!(get(&app, "/small").await.status, StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn configuration_is_read_from_the_environment() {
+    let (page, invalid) = {
+        let _env = ENV.lock().unwrap();
+        env::set_var("TERA_PAGE_PREFIX", "content");
+        env::set_var("TERA_PAGE_EXTENSION", "tera");
+        env::set_var("TERA_PAGE_INDEX", "home");
+        let page = TeraPage::from_env(|_| async { Context::new() });
+        env::set_var("TERA_PAGE_EXTENSION", "tera/x");
+        let invalid = TeraPage::from_env(|_| async { Context::new() });
+        for var in ["TERA_PAGE_PREFIX", "TERA_PAGE_EXTENSION", "TERA_PAGE_INDEX"] {
+            env::remove_var(var);
+        }
+        (page.unwrap(), invalid)
+    };
+    assert!(matches!(invalid, Err(ConfigError::InvalidEnv { .. })));
+
+    let summary = page.describe();
+    assert_eq!(summary.prefix, "content");
+    assert_eq!(summary.extension, "tera");
+    assert_eq!(summary.index_name, "home");
+    let app = app(page, tera(&[("content/home.tera", "home")])).await;
+    assert_eq!(get(&app, "/").await.text(), "home");
+}
+
+#[actix_web::test]
+async fn shared_context_is_refreshed_in_the_background() {
+    let refreshes = Arc::new(AtomicUsize::new(0));
+    let counter = refreshes.clone();
+    let page =
+        TeraPage::static_only("pages").shared_context(Duration::from_millis(10), move || {
+            let refresh = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                let mut context = Context::new();
+                context.insert("refresh", &refresh);
+                context
+            }
+        });
+    let app = app(page, tera(&[("pages/index.html", "{{ refresh }}")])).await;
+
+    rt::time::sleep(Duration::from_millis(50)).await;
+    let first = get(&app, "/").await.text().parse::<usize>().unwrap();
+    assert!(first >= 1);
+
+    rt::time::sleep(Duration::from_millis(50)).await;
+    let second = get(&app, "/").await.text().parse::<usize>().unwrap();
+    assert!(second > first);
+    assert!(refreshes.load(Ordering::SeqCst) >= second);
+}
+
+#[actix_web::test]
+async fn configure_lets_pages_render_without_routes() {
+    let tera = tera(&[("pages/about.html", "about")]);
+    let app = test::init_service(
+        App::new().app_data(Data::new(tera)).service(
+            web::scope("")
+                .configure(TeraPage::configure)
+                .wrap(TeraPage::static_only("pages")),
+        ),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/about").await.text(), "about");
+    assert_eq!(get(&app, "/missing").await.status, StatusCode::NOT_FOUND);
+}
+
+#[actix_web::test]
+async fn noindex_paths_get_the_robots_header() {
+    let tera = tera(&[
+        ("pages/drafts/post.html", "draft"),
+        ("pages/post.html", "post"),
+    ]);
+    let app = app(TeraPage::static_only("pages").noindex(&["/drafts"]), tera).await;
+
+    let res = get(&app, "/drafts/post").await;
+    assert_eq!(res.header("x-robots-tag"), Some("noindex, nofollow"));
+    assert_eq!(get(&app, "/post").await.header("x-robots-tag"), None);
+}
+
+#[actix_web::test]
+async fn principal_is_inserted_as_user() {
+    struct Principal(&'static str);
+
+    impl IntoContextValue for Principal {
+        fn context_value(&self) -> Value {
+            self.0.into()
+        }
+    }
+
+    let tera = tera(&[(
+        "pages/index.html",
+        "{{ user | default(value='anonymous') }}",
+    )]);
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera))
+            .wrap(TeraPage::static_only("pages").principal::<Principal>())
+            .wrap_fn(|req, srv| {
+                if req.headers().contains_key("authorization") {
+                    req.extensions_mut().insert(Principal("ada"));
+                }
+                srv.call(req)
+            }),
+    )
+    .await;
+
+    let signed_in = TestRequest::get()
+        .uri("/")
+        .insert_header(("authorization", "Bearer token"));
+    assert_eq!(call(&app, signed_in).await.text(), "ada");
+    assert_eq!(get(&app, "/").await.text(), "anonymous");
+}
+
+#[cfg(feature = "static-cache")]
+#[actix_web::test]
+async fn static_pages_for_principals_are_not_shared() {
+    struct Principal(String);
+
+    impl IntoContextValue for Principal {
+        fn context_value(&self) -> Value {
+            self.0.as_str().into()
+        }
+    }
+
+    let builds = Arc::new(AtomicUsize::new(0));
+    let counted = builds.clone();
+    let page = TeraPage::new("pages", move |_| {
+        counted.fetch_add(1, Ordering::SeqCst);
+        async { Context::new() }
+    })
+    .principal::<Principal>()
+    .static_pages(&["/account"]);
+    let tera = tera(&[(
+        "pages/account.html",
+        "{{ user | default(value='anonymous') }}",
+    )]);
+    let app = test::init_service(App::new().app_data(Data::new(tera)).wrap(page).wrap_fn(
+        |req, srv| {
+            if let Some(user) = req.headers().get("x-user") {
+                let user = user.to_str().unwrap().to_string();
+                req.extensions_mut().insert(Principal(user));
+            }
+            srv.call(req)
+        },
+    ))
+    .await;
+
+    let as_user = |user| {
+        TestRequest::get()
+            .uri("/account")
+            .insert_header(("x-user", user))
+    };
+    assert_eq!(call(&app, as_user("ada")).await.text(), "ada");
+    assert_eq!(call(&app, as_user("grace")).await.text(), "grace");
+    assert_eq!(get(&app, "/account").await.text(), "anonymous");
+    assert_eq!(get(&app, "/account").await.text(), "anonymous");
+    assert_eq!(call(&app, as_user("ada")).await.text(), "ada");
+    assert_eq!(builds.load(Ordering::SeqCst), 4);
+}
+
+#[cfg(all(feature = "static-cache", feature = "regex"))]
+#[actix_web::test]
+async fn static_pages_are_cached_per_path_and_locale() {
+    let page = TeraPage::static_only("pages")
+        .match_strategy(MatchStrategy::Regex(vec![(
+            Regex::new(r"^/blog/(?P<id>\d+)$").unwrap(),
+            "pages/post.html".to_string(),
+        )]))
+        .locales(&["en", "fr"])
+        .static_pages(&["/blog"]);
+    let app = app(
+        page,
+        tera(&[("pages/post.html", "{{ locale }} {{ captures.id }}")]),
+    )
+    .await;
+
+    let french = |uri| {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header(("accept-language", "fr"))
+    };
+    for _ in 0..2 {
+        assert_eq!(get(&app, "/blog/1").await.text(), "en 1");
+        assert_eq!(get(&app, "/blog/2").await.text(), "en 2");
+        assert_eq!(call(&app, french("/blog/1")).await.text(), "fr 1");
+    }
+}
+
+#[cfg(feature = "static-cache")]
+#[actix_web::test]
+async fn static_pages_answer_range_requests() {
+    let tera = tera(&[
+        ("pages/terms.html", "0123456789"),
+        ("pages/news.html", "0123456789"),
+    ]);
+    let app = app(
+        TeraPage::static_only("pages").static_pages(&["/terms"]),
+        tera,
+    )
+    .await;
+
+    let range = |uri| {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header(("range", "bytes=2-5"))
+    };
+    let res = call(&app, range("/terms")).await;
+    assert_eq!(res.status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.header("content-range"), Some("bytes 2-5/10"));
+    assert_eq!(res.text(), "2345");
+
+    let res = call(&app, range("/news")).await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.text(), "0123456789");
+}
+
+#[actix_web::test]
+async fn redaction_covers_every_template_in_the_error() {
+    #[derive(Default)]
+    struct Errors(Mutex<Vec<String>>);
+
+    impl TeraPageLogger for Errors {
+        fn render_error(&self, template: &str, message: &str) {
+            let mut errors = self.0.lock().unwrap();
+            errors.push(format!("{} {}", template, message));
+        }
+    }
+
+    let tera = tera(&[
+        ("layouts/base.html", "{% include 'partials/nav.html' %}"),
+        ("partials/nav.html", "{{ missing }}"),
+        (
+            "pages/admin/secret.html",
+            "{% extends 'layouts/base.html' %}",
+        ),
+    ]);
+    let errors = Arc::new(Errors::default());
+    let page = TeraPage::static_only("pages")
+        .redact_template_paths(true)
+        .logger(errors.clone());
+    let app = app(page, tera).await;
+
+    let res = get(&app, "/admin/secret").await;
+    assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+    let errors = errors.0.lock().unwrap();
+    let logged = errors.join("\n");
+    assert!(logged.contains("template-"), "{}", logged);
+    for name in ["admin/secret", "base.html", "nav.html"] {
+        assert!(!logged.contains(name), "{}", logged);
+        assert!(!res.text().contains(name), "{}", res.text());
+    }
+}
+
+#[actix_web::test]
+async fn json_templates_are_served_as_json() {
+    let tera = tera(&[("pages/config.json", r#"{"name": "{{ name }}"}"#)]);
+    let page = TeraPage::new("pages", |_| async {
+        let mut context = Context::new();
+        context.insert("name", "site");
+        context
+    });
+    let app = app(page, tera).await;
+
+    let res = get(&app, "/config.json").await;
+    assert_eq!(res.header("content-type"), Some("application/json"));
+    assert_eq!(res.text(), r#"{"name": "site"}"#);
+}
+
+#[cfg(feature = "cache-config")]
+#[actix_web::test]
+async fn cache_config_sets_cache_control() {
+    let path = env::temp_dir().join(format!("tera-page-cache-{}.toml", std::process::id()));
+    fs::write(&path, r#""pages/about.html" = "max-age=300""#).unwrap();
+    let page = TeraPage::static_only("pages")
+        .cache_config(path.to_str().unwrap())
+        .unwrap();
+    fs::remove_file(&path).unwrap();
+
+    let tera = tera(&[("pages/about.html", "about"), ("pages/news.html", "news")]);
+    let app = app(page, tera).await;
+    assert_eq!(
+        get(&app, "/about").await.header("cache-control"),
+        Some("max-age=300")
+    );
+    assert_eq!(get(&app, "/news").await.header("cache-control"), None);
+
+    let missing = TeraPage::static_only("pages").cache_config(path.to_str().unwrap());
+    assert!(missing.is_ok());
+}
+
+#[actix_web::test]
+async fn disabled_middleware_falls_through() {
+    let enabled = Arc::new(AtomicBool::new(true));
+    let page = TeraPage::static_only("pages").enabled(enabled.clone());
+    let app = app(page, tera(&[("pages/about.html", "about")])).await;
+
+    assert_eq!(get(&app, "/about").await.text(), "about");
+    enabled.store(false, Ordering::Relaxed);
+    assert_eq!(get(&app, "/about").await.text(), INNER);
+    enabled.store(true, Ordering::Relaxed);
+    assert_eq!(get(&app, "/about").await.text(), "about");
+}
+
+#[actix_web::test]
+async fn compose_wraps_the_page_in_fragments() {
+    let tera = tera(&[
+        ("partials/header.html", "<header>"),
+        ("partials/footer.html", "<footer>"),
+        ("pages/about.html", "about"),
+    ]);
+    let page = TeraPage::static_only("pages").compose(&[
+        "partials/header.html",
+        TeraPage::COMPOSED_PAGE,
+        "partials/footer.html",
+    ]);
+    let app = app(page, tera).await;
+
+    assert_eq!(get(&app, "/about").await.text(), "<header>about<footer>");
+}
+
+#[actix_web::test]
+async fn templates_can_redirect() {
+    let tera = tera(&[
+        ("pages/old.html", "REDIRECT: /new\nignored"),
+        ("pages/new.html", "new"),
+    ]);
+    let app = app(
+        TeraPage::static_only("pages").allow_template_redirects(true),
+        tera,
+    )
+    .await;
+
+    let res = get(&app, "/old").await;
+    assert_eq!(res.status, StatusCode::FOUND);
+    assert_eq!(res.header("location"), Some("/new"));
+    assert_eq!(get(&app, "/new").await.text(), "new");
+}
+
+#[actix_web::test]
+async fn forced_autoescape_escapes_other_extensions() {
+    let page = TeraPage::new("pages", |_| async {
+        let mut context = Context::new();
+        context.insert("title", "<script>alert(1)</script>");
+        context
+    });
+    let tera = tera(&[("pages/post.md", "# {{ title }}")]);
+    let app = app(page.extension("md").force_autoescape(true), tera).await;
+
+    let res = get(&app, "/post").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.text(), "# &lt;script&gt;alert(1)&lt;&#x2F;script&gt;");
+}
+
+#[actix_web::test]
+async fn metrics_count_requests_by_outcome() {
+    let page = TeraPage::static_only("pages");
+    let metrics = page.metrics();
+    let tera = tera(&[
+        ("pages/about.html", "about"),
+        ("pages/broken.html", "{{ x }}"),
+    ]);
+    let app = app(page, tera).await;
+
+    for uri in ["/about", "/about", "/missing", "/broken"] {
+        get(&app, uri).await;
+    }
+    assert_eq!(metrics.requests(), 4);
+    assert_eq!(metrics.matched(), 3);
+    assert_eq!(metrics.rendered(), 2);
+    assert_eq!(metrics.failed(), 1);
+    assert_eq!(metrics.fallthroughs(), 1);
+}
+
+#[actix_web::test]
+async fn ambiguous_matches_follow_the_configured_policy() {
+    let templates = [
+        ("pages/foo.html", "foo.html"),
+        ("pages/foo/index.html", "foo/index.html"),
+    ];
+
+    let app_with = |ambiguity| {
+        app(
+            TeraPage::static_only("pages").on_ambiguous(ambiguity),
+            tera(&templates),
+        )
+    };
+    let res = get(&app_with(Ambiguity::Error).await, "/foo").await;
+    assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(res.text().contains("pages/foo.html"), "{}", res.text());
+    assert!(
+        res.text().contains("pages/foo/index.html"),
+        "{}",
+        res.text()
+    );
+
+    let res = get(&app_with(Ambiguity::FirstWins).await, "/foo").await;
+    assert_eq!(res.text(), "foo.html");
+    let res = get(&app_with(Ambiguity::LastWins).await, "/foo").await;
+    assert_eq!(res.text(), "foo/index.html");
+}
+
+#[actix_web::test]
+async fn template_source_is_injected() {
+    let tera = tera(&[(
+        "pages/docs/intro.html",
+        "edit {{ template_source.name | safe }}",
+    )]);
+    let app = app(
+        TeraPage::static_only("pages").inject_template_source(true),
+        tera,
+    )
+    .await;
+
+    assert_eq!(
+        get(&app, "/docs/intro").await.text(),
+        "edit pages/docs/intro.html"
+    );
+}
+
+#[actix_web::test]
+async fn embedded_templates_resolve_like_loaded_ones() {
+    let mut tera = Tera::default();
+    for (name, source) in [
+        ("pages/index.html", "home"),
+        ("pages/guide/index.html", "guide"),
+        ("pages/guide/setup.html", "setup"),
+    ] {
+        tera.add_raw_template(name, source).unwrap();
+    }
+    let page = TeraPage::new("/pages/", |_| async { Context::new() });
+    let app = app(page, tera).await;
+
+    assert_eq!(get(&app, "/").await.text(), "home");
+    assert_eq!(get(&app, "/guide/").await.text(), "guide");
+    assert_eq!(get(&app, "/guide/setup").await.text(), "setup");
+}
+
+#[actix_web::test]
+async fn wraps_services_with_other_error_types() {
+    let inner = fn_service(|req: ServiceRequest| async move {
+        let res = HttpResponse::NotFound().body(INNER);
+        Ok::<_, Infallible>(req.into_response(res))
+    });
+    let middleware = TeraPage::static_only("pages")
+        .new_transform(inner)
+        .await
+        .unwrap();
+
+    let tera = Data::new(tera(&[("pages/about.html", "about")]));
+    for (uri, body) in [("/about", "about"), ("/missing", INNER)] {
+        let req = TestRequest::get()
+            .uri(uri)
+            .app_data(tera.clone())
+            .to_srv_request();
+        let res = middleware.call(req).await.unwrap();
+        assert_eq!(test::read_body(res).await, body);
+    }
+}
+
+#[actix_web::test]
+async fn content_length_is_left_to_actix_web() {
+    let body = "café ☕";
+    let tera = tera(&[("pages/about.html", body)]);
+    let app = app(TeraPage::static_only("pages"), tera.clone()).await;
+
+    // A sized body is what makes actix-web send `Content-Length`, with its byte length.
+    let res = test::call_service(&app, TestRequest::get().uri("/about").to_request()).await;
+    assert_eq!(
+        res.response().body().size(),
+        BodySize::Sized(body.len() as u64)
+    );
+    assert!(!res.headers().contains_key("content-length"));
+
+    // A length set by the middleware would outlive re-encoding by `Compress`.
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera))
+            .wrap(TeraPage::static_only("pages"))
+            .wrap(Compress::default()),
+    )
+    .await;
+    let zstd = TestRequest::get()
+        .uri("/about")
+        .insert_header(("accept-encoding", "zstd"));
+    let res = call(&app, zstd).await;
+    assert_eq!(res.header("content-encoding"), Some("zstd"));
+    assert_eq!(res.header("content-length"), None);
+}
+
+#[actix_web::test]
+async fn tera_can_be_registered_in_a_newtype() {
+    struct AppTera(Tera);
+
+    impl AsRef<Tera> for AppTera {
+        fn as_ref(&self) -> &Tera {
+            &self.0
+        }
+    }
+
+    let tera = AppTera(tera(&[("pages/about.html", "about")]));
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera))
+            .wrap(TeraPage::static_only("pages").tera_type::<AppTera>()),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/about").await.text(), "about");
+}
+
+#[actix_web::test]
+async fn subdomains_select_the_prefix() {
+    let tera = tera(&[
+        ("pages/brand-a/about.html", "brand a"),
+        ("pages/brand-b/about.html", "brand b"),
+        ("pages/about.html", "default"),
+    ]);
+    let page = TeraPage::static_only("pages").subdomain_prefix(|subdomain| {
+        subdomain
+            .starts_with("brand-")
+            .then(|| format!("pages/{}", subdomain))
+    });
+    let app = app(page, tera).await;
+
+    for (host, body) in [
+        ("brand-a.example.com", "brand a"),
+        ("brand-b.example.com:8080", "brand b"),
+        ("www.example.com", "default"),
+        ("example.com", "default"),
+    ] {
+        let req = TestRequest::get()
+            .uri("/about")
+            .insert_header(("host", host));
+        assert_eq!(call(&app, req).await.text(), body, "{}", host);
+    }
+}
+
+#[actix_web::test]
+async fn subdomains_are_all_but_the_last_two_labels() {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let recorded = seen.clone();
+    let page = TeraPage::static_only("pages").subdomain_prefix(move |subdomain| {
+        recorded.lock().unwrap().push(subdomain.to_string());
+        Some("pages/brand-a".to_string())
+    });
+    let app = app(page, tera(&[("pages/brand-a/about.html", "brand a")])).await;
+    for host in ["brand-a.example.co.uk", "10.0.0.1", "[::1]:8080"] {
+        let req = TestRequest::get()
+            .uri("/about")
+            .insert_header(("host", host));
+        call(&app, req).await;
+    }
+    assert_eq!(*seen.lock().unwrap(), ["brand-a.example"]);
+}
+
+#[actix_web::test]
+async fn unusual_output_is_served_without_panicking() {
+    let odd = "\u{0}\u{feff}\u{fffd}\u{10ffff}<style>\u{200b}</style>";
+    let mut tera = tera(&[("pages/odd.html", "{{ '' | odd | safe }}")]);
+    tera.register_filter("odd", move |_: &Value, _: &HashMap<String, Value>| {
+        Ok(Value::String(odd.to_string()))
+    });
+    let page = TeraPage::static_only("pages").allow_template_redirects(true);
+    #[cfg(feature = "csp")]
+    let page = page.csp_hashes(true);
+    let app = app(page, tera).await;
+
+    let res = get(&app, "/odd").await;
+    assert_eq!(res.status, StatusCode::OK);
+    assert_eq!(res.text(), odd);
+}
+
+#[actix_web::test]
+async fn pages_are_sent_with_the_success_status() {
+    let tera = tera(&[("pages/confirmed.html", "confirmed")]);
+    let page = TeraPage::static_only("pages").success_status(StatusCode::CREATED);
+    let app = app(page, tera).await;
+
+    let res = get(&app, "/confirmed").await;
+    assert_eq!(res.status, StatusCode::CREATED);
+    assert_eq!(res.text(), "confirmed");
+}
+
+#[actix_web::test]
+async fn context_providers_build_the_context() {
+    /// Stands in for a database connection pool.
+    struct Pool {
+        users: Vec<&'static str>,
+    }
+
+    struct Members {
+        pool: Pool,
+    }
+
+    impl ContextProvider for Members {
+        fn build<'a>(&'a self, _req: &'a HttpRequest) -> LocalBoxFuture<'a, Context> {
+            Box::pin(async move {
+                let mut context = Context::new();
+                context.insert("users", &self.pool.users);
+                context
+            })
+        }
+    }
+
+    let provider = Arc::new(Members {
+        pool: Pool {
+            users: vec!["ada", "grace"],
+        },
+    });
+    let tera = tera(&[("pages/members.html", "{{ users | join(sep=', ') }}")]);
+    let app = app(TeraPage::with_provider("pages", provider), tera).await;
+
+    assert_eq!(get(&app, "/members").await.text(), "ada, grace");
+}
+
+#[actix_web::test]
+async fn blocked_requests_get_451() {
+    let blocked = |req: &HttpRequest| {
+        req.headers()
+            .get("x-country")
+            .is_some_and(|country| country == "XX")
+    };
+    let templates = [
+        ("pages/article.html", "article"),
+        ("pages/451.html", "blocked here"),
+    ];
+    let page = TeraPage::static_only("pages").legal_block(blocked, "pages/451.html");
+    let blocking = app(page, tera(&templates)).await;
+
+    let from = |country| {
+        TestRequest::get()
+            .uri("/article")
+            .insert_header(("x-country", country))
+    };
+    let res = call(&blocking, from("XX")).await;
+    assert_eq!(res.status, StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    assert_eq!(res.text(), "blocked here");
+    assert_eq!(call(&blocking, from("FR")).await.text(), "article");
+
+    // Without a template, the reason phrase is sent.
+    let page = TeraPage::static_only("pages").legal_block(blocked, "pages/missing.html");
+    let plain = app(page, tera(&templates)).await;
+    let res = call(&plain, from("XX")).await;
+    assert_eq!(res.status, StatusCode::UNAVAILABLE_FOR_LEGAL_REASONS);
+    assert_eq!(res.text(), "Unavailable For Legal Reasons");
+}
+
+#[actix_web::test]
+async fn skipped_prefixes_fall_through() {
+    let templates = [
+        ("pages/.well-known/security.txt.html", "security"),
+        ("pages/internal/status.html", "status"),
+    ];
+    let app_default = app(TeraPage::static_only("pages"), tera(&templates)).await;
+    let res = get(&app_default, "/.well-known/security.txt").await;
+    assert_eq!(res.text(), INNER);
+    assert_eq!(get(&app_default, "/internal/status").await.text(), "status");
+
+    let page = TeraPage::static_only("pages").skip_prefixes(&["/internal"]);
+    let app_custom = app(page, tera(&templates)).await;
+    assert_eq!(get(&app_custom, "/internal/status").await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn concurrent_renders_are_limited() {
+    let slow = || {
+        TeraPage::new("pages", |_| async {
+            rt::time::sleep(Duration::from_millis(100)).await;
+            Context::new()
+        })
+        .max_concurrent_renders(1)
+    };
+    let templates = [("pages/slow.html", "slow")];
+
+    let queueing = app(slow(), tera(&templates)).await;
+    let started = Instant::now();
+    let (first, second) = join!(get(&queueing, "/slow"), get(&queueing, "/slow"));
+    assert_eq!(
+        (first.status, second.status),
+        (StatusCode::OK, StatusCode::OK)
+    );
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    let failing = app(slow().fail_fast(true), tera(&templates)).await;
+    let (first, second) = join!(get(&failing, "/slow"), get(&failing, "/slow"));
+    let mut statuses = [first.status, second.status];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE]);
+}
+
+#[actix_web::test]
+async fn cookies_select_the_experiment_variant() {
+    let page = TeraPage::new("pages", |_| async { Context::new() }).ab_variant(|req| {
+        req.cookie("experiment")
+            .map(|cookie| cookie.value().to_string())
+    });
+    let app = app(
+        page,
+        tera(&[
+            ("pages/landing.html", "A"),
+            ("pages/landing.b.html", "B {{ ab_variant }}"),
+        ]),
+    )
+    .await;
+
+    let page = get(&app, "/landing").await;
+    assert_eq!(page.text(), "A");
+    assert!(page.header_values("vary").contains(&"cookie"));
+
+    let req = TestRequest::get()
+        .uri("/landing")
+        .cookie(actix_web::cookie::Cookie::new("experiment", "b"));
+    let page = call(&app, req).await;
+    assert_eq!(page.text(), "B b");
+    assert!(page.header_values("vary").contains(&"cookie"));
+}
+
+#[cfg(all(feature = "static-cache", feature = "test-util"))]
+#[actix_web::test]
+async fn warmed_pages_are_served_without_rendering() {
+    let templates = [("pages/about.html", "{{ site }}: {{ title }}")];
+    let mut globals = Context::new();
+    globals.insert("site", "Example");
+    let mut warm = Context::new();
+    warm.insert("title", "About");
+    let builds = Arc::new(AtomicUsize::new(0));
+    let counted = builds.clone();
+    let page = TeraPage::new("pages", move |_| {
+        counted.fetch_add(1, Ordering::SeqCst);
+        async { Context::new() }
+    })
+    .globals(globals)
+    .post_render_async(|body, req| {
+        let path = req.path().to_string();
+        async move { format!("{body} at {path}") }
+    })
+    .static_pages(&["/about"])
+    .warm_cache(&tera(&templates), &warm, &["/about"]);
+    let metrics = page.metrics();
+    let app = app(page, tera(&templates)).await;
+    assert_eq!(metrics.template_renders(), 1);
+
+    for _ in 0..2 {
+        assert_eq!(get(&app, "/about").await.text(), "Example: About at /about");
+    }
+    assert_eq!(metrics.template_renders(), 1);
+    assert_eq!(builds.load(Ordering::SeqCst), 0);
+}
+
+#[actix_web::test]
+async fn preloaded_assets_are_linked() {
+    let assets = ["/app.css", "/app.js", "/font.woff2"].map(String::from);
+    let page = TeraPage::new("pages", |_| async { Context::new() }).preload_map(HashMap::from([(
+        "pages/about.html".to_string(),
+        assets.to_vec(),
+    )]));
+    let app = app(
+        page,
+        tera(&[("pages/about.html", "about"), ("pages/team.html", "team")]),
+    )
+    .await;
+
+    let page = get(&app, "/about").await;
+    assert_eq!(
+        page.header_values("link"),
+        [
+            "</app.css>; rel=preload; as=style",
+            "</app.js>; rel=preload; as=script",
+            "</font.woff2>; rel=preload; as=font; crossorigin",
+        ]
+    );
+    assert!(get(&app, "/team").await.header("link").is_none());
+}
+
+#[actix_web::test]
+async fn template_header_picks_the_page() {
+    let page =
+        TeraPage::new("pages", |_| async { Context::new() }).template_header(Some("x-template"));
+    let app = app(
+        page,
+        tera(&[
+            ("pages/about.html", "about"),
+            ("pages/promo.html", "promo"),
+            ("private/keys.html", "keys"),
+        ]),
+    )
+    .await;
+
+    let header = |name| {
+        TestRequest::get()
+            .uri("/about")
+            .insert_header(("x-template", name))
+    };
+    assert_eq!(call(&app, header("pages/promo.html")).await.text(), "promo");
+    assert_eq!(
+        call(&app, header("private/keys.html")).await.text(),
+        "about"
+    );
+    assert_eq!(
+        call(&app, header("pages/../private/keys.html"))
+            .await
+            .text(),
+        "about"
+    );
+}
+
+#[actix_web::test]
+async fn no_cache_requests_get_a_fresh_shared_context() {
+    let refreshes = Arc::new(AtomicUsize::new(0));
+    let counter = refreshes.clone();
+    let page = TeraPage::static_only("pages")
+        .shared_context(Duration::from_secs(3600), move || {
+            let refresh = counter.fetch_add(1, Ordering::SeqCst) + 1;
+            async move {
+                let mut context = Context::new();
+                context.insert("refresh", &refresh);
+                context
+            }
+        })
+        .honor_request_no_cache(|req| req.headers().contains_key("x-editor"));
+    let app = app(page, tera(&[("pages/index.html", "{{ refresh }}")])).await;
+    rt::time::sleep(Duration::from_millis(10)).await;
+
+    assert_eq!(get(&app, "/").await.text(), "1");
+    let no_cache = || {
+        TestRequest::get()
+            .uri("/")
+            .insert_header(("cache-control", "no-cache"))
+    };
+    assert_eq!(call(&app, no_cache()).await.text(), "1");
+    let editor = no_cache().insert_header(("x-editor", "1"));
+    assert_eq!(call(&app, editor).await.text(), "2");
+    assert_eq!(get(&app, "/").await.text(), "2");
+    assert_eq!(refreshes.load(Ordering::SeqCst), 2);
+}
+
+#[actix_web::test]
+async fn static_only_pages_render_with_an_empty_context() {
+    let app = app(
+        TeraPage::static_only("pages").inject_path(true),
+        tera(&[(
+            "pages/terms.html",
+            "Terms at {{ path | safe }}{{ title | default(value='') }}",
+        )]),
+    )
+    .await;
+
+    let page = get(&app, "/terms").await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert_eq!(page.text(), "Terms at /terms");
+}
+
+#[actix_web::test]
+async fn globals_sit_beneath_the_built_context() {
+    let mut globals = Context::new();
+    globals.insert("site", "Example");
+    globals.insert("title", "Untitled");
+    let page = TeraPage::new("pages", |req: HttpRequest| async move {
+        let mut context = Context::new();
+        if req.path() == "/about" {
+            context.insert("title", "About");
+        }
+        context
+    })
+    .globals(globals);
+    let app = app(
+        page,
+        tera(&[
+            ("pages/about.html", "{{ title }} - {{ site }}"),
+            ("pages/faq.html", "{{ title }} - {{ site }}"),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/about").await.text(), "About - Example");
+    assert_eq!(get(&app, "/faq").await.text(), "Untitled - Example");
+}
+
+#[actix_web::test]
+async fn directories_without_an_index_are_listed() {
+    let page = TeraPage::static_only("pages").autoindex(Some("listing.html"));
+    let app = app(
+        page,
+        tera(&[
+            (
+                "listing.html",
+                "{% for entry in entries %}{{ entry | safe }} {% endfor %}",
+            ),
+            ("pages/docs/intro.html", "intro"),
+            ("pages/docs/guide/start.html", "start"),
+            ("pages/blog/index.html", "blog"),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/docs").await.text(), "/docs/guide/ /docs/intro ");
+    assert_eq!(get(&app, "/blog").await.text(), "blog");
+    assert_eq!(get(&app, "/missing").await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn error_responses_get_the_status_page() {
+    let page = TeraPage::new("pages", |_| async {
+        let mut context = Context::new();
+        context.insert("site", "Example");
+        context
+    });
+    let error_pages = page.with_error_handlers();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera(&[(
+                "pages/404.html",
+                "{{ site }}: not found",
+            )])))
+            .wrap(page)
+            .wrap(error_pages)
+            .route(
+                "/item",
+                web::get().to(|| async { HttpResponse::NotFound().body("raw") }),
+            )
+            .route(
+                "/broken",
+                web::get().to(|| async { HttpResponse::InternalServerError().body("raw") }),
+            ),
+    )
+    .await;
+
+    let page = call(
+        &app,
+        TestRequest::get()
+            .uri("/item")
+            .insert_header(("accept", "text/html")),
+    )
+    .await;
+    assert_eq!(page.status, StatusCode::NOT_FOUND);
+    assert_eq!(page.text(), "Example: not found");
+
+    let page = call(
+        &app,
+        TestRequest::get()
+            .uri("/broken")
+            .insert_header(("accept", "text/html")),
+    )
+    .await;
+    assert_eq!(page.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(page.text(), "raw");
+}
+
+#[cfg(all(feature = "brotli", feature = "gzip"))]
+#[actix_web::test]
+async fn pages_are_compressed_as_negotiated() {
+    use std::io::Read;
+
+    let body = "compressible ".repeat(100);
+    let app = app(
+        TeraPage::static_only("pages"),
+        tera(&[("pages/index.html", &body)]),
+    )
+    .await;
+    let encoded = |accept: &'static str| {
+        call(
+            &app,
+            TestRequest::get()
+                .uri("/")
+                .insert_header(("accept-encoding", accept)),
+        )
+    };
+
+    let page = encoded("gzip, br").await;
+    assert_eq!(page.header("content-encoding"), Some("br"));
+    let mut decoded = String::new();
+    brotli::Decompressor::new(&page.body[..], 4096)
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, body);
+
+    let page = encoded("br;q=0.5, gzip").await;
+    assert_eq!(page.header("content-encoding"), Some("gzip"));
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&page.body[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, body);
+
+    let page = encoded("identity").await;
+    assert_eq!(page.header("content-encoding"), None);
+    assert_eq!(page.text(), body);
+    assert!(page.header_values("vary").contains(&"accept-encoding"));
+}
+
+#[cfg(all(feature = "brotli", feature = "gzip"))]
+#[actix_web::test]
+async fn etags_are_tagged_with_the_encoding() {
+    let page = TeraPage::static_only("pages").etag_fn(|_, _| Some("v1".to_string()));
+    let app = app(page, tera(&[("pages/index.html", "index")])).await;
+    let request = |accept: &'static str, tag: &'static str| {
+        TestRequest::get()
+            .uri("/")
+            .insert_header(("accept-encoding", accept))
+            .insert_header(("if-none-match", tag))
+    };
+
+    for (accept, etag) in [
+        ("br", "\"v1-br\""),
+        ("gzip", "\"v1-gzip\""),
+        ("identity", "\"v1\""),
+    ] {
+        let page = call(&app, request(accept, "\"v0\"")).await;
+        assert_eq!(page.header("etag"), Some(etag));
+        let page = call(&app, request(accept, etag)).await;
+        assert_eq!(page.status, StatusCode::NOT_MODIFIED);
+    }
+    let page = call(&app, request("gzip", "\"v1-br\"")).await;
+    assert_eq!(page.status, StatusCode::OK);
+}
+
+#[cfg(all(feature = "static-cache", feature = "test-util", feature = "brotli"))]
+#[actix_web::test]
+async fn static_pages_are_compressed_once() {
+    let page = TeraPage::static_only("pages").static_pages(&["/about", "/news"]);
+    let metrics = page.metrics();
+    let app = app(
+        page,
+        tera(&[("pages/about.html", "about"), ("pages/news.html", "news")]),
+    )
+    .await;
+    let compressed = |uri: &'static str| {
+        let req = TestRequest::get()
+            .uri(uri)
+            .insert_header(("accept-encoding", "br"));
+        call(&app, req)
+    };
+
+    let first = compressed("/about").await;
+    assert_eq!(first.header("content-encoding"), Some("br"));
+    for _ in 0..3 {
+        assert_eq!(compressed("/about").await.body, first.body);
+    }
+    assert_eq!(metrics.compressions(), 1);
+
+    compressed("/news").await;
+    assert_eq!(get(&app, "/about").await.text(), "about");
+    assert_eq!(metrics.compressions(), 2);
+}
+
+#[actix_web::test]
+async fn candidate_stages_are_chained() {
+    let lowercase: CandidateTransform =
+        Box::new(|candidates| candidates.iter().map(|c| c.to_lowercase()).collect());
+    let alias: CandidateTransform = Box::new(|candidates| {
+        candidates
+            .into_iter()
+            .map(|c| c.replace("pages/faq", "pages/help"))
+            .collect()
+    });
+    let page = TeraPage::static_only("pages").candidate_pipeline(vec![lowercase, alias]);
+    let app = app(page, tera(&[("pages/help.html", "help")])).await;
+
+    assert_eq!(get(&app, "/FAQ").await.text(), "help");
+    assert_eq!(get(&app, "/Help").await.text(), "help");
+}
+
+#[actix_web::test]
+async fn request_ids_are_injected_and_echoed() {
+    let page = TeraPage::static_only("pages").request_id("x-request-id");
+    let app = app(page, tera(&[("pages/index.html", "{{ request_id }}")])).await;
+
+    let page = call(
+        &app,
+        TestRequest::get()
+            .uri("/")
+            .insert_header(("x-request-id", "abc123")),
+    )
+    .await;
+    assert_eq!(page.text(), "abc123");
+    assert_eq!(page.header("x-request-id"), Some("abc123"));
+
+    let page = get(&app, "/").await;
+    assert!(!page.text().is_empty());
+    assert_eq!(page.header("x-request-id"), Some(page.text()));
+
+    let templates = [("pages/index.html", "{{ request_id }}")];
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera(&templates)))
+            .wrap(TeraPage::static_only("pages").request_id("x-request-id"))
+            .wrap_fn(|req, srv| {
+                req.extensions_mut().insert(RequestId("upstream".into()));
+                srv.call(req)
+            }),
+    )
+    .await;
+    let page = call(
+        &app,
+        TestRequest::get()
+            .uri("/")
+            .insert_header(("x-request-id", "abc123")),
+    )
+    .await;
+    assert_eq!(page.text(), "upstream");
+    assert_eq!(page.header("x-request-id"), Some("upstream"));
+}
+
+#[actix_web::test]
+async fn ignored_templates_are_never_served() {
+    let page = TeraPage::static_only("pages").ignore_pattern("_*");
+    let app = app(
+        page,
+        tera(&[
+            ("pages/_partial.html", "partial"),
+            (
+                "pages/index.html",
+                "{% include \"pages/_partial.html\" %} index",
+            ),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/").await.text(), "partial index");
+    assert_eq!(get(&app, "/_partial").await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn attachments_are_served_for_download() {
+    let page = TeraPage::static_only("pages")
+        .attachment(|template, _| template.ends_with(".csv").then(|| "report.csv".to_string()));
+    let app = app(
+        page,
+        tera(&[
+            ("pages/report.csv", "a,b\n1,2\n"),
+            ("pages/index.html", "index"),
+        ]),
+    )
+    .await;
+
+    let page = get(&app, "/report.csv").await;
+    assert_eq!(page.text(), "a,b\n1,2\n");
+    assert_eq!(page.header("content-type"), Some("text/csv; charset=utf-8"));
+    assert_eq!(
+        page.header("content-disposition"),
+        Some("attachment; filename=\"report.csv\"")
+    );
+    assert!(get(&app, "/").await.header("content-disposition").is_none());
+}
+
+#[actix_web::test]
+async fn maintenance_mode_answers_with_503() {
+    let flag = Arc::new(AtomicBool::new(false));
+    let page = TeraPage::static_only("pages")
+        .maintenance(flag.clone(), "maintenance.html")
+        .maintenance_allowlist(&["/health"]);
+    let app = app(
+        page,
+        tera(&[
+            ("maintenance.html", "back soon"),
+            ("pages/about.html", "about"),
+            ("pages/health.html", "ok"),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/about").await.text(), "about");
+
+    flag.store(true, Ordering::SeqCst);
+    for path in ["/about", "/missing"] {
+        let page = get(&app, path).await;
+        assert_eq!(page.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(page.text(), "back soon");
+        assert_eq!(page.header("retry-after"), Some("300"));
+    }
+    assert_eq!(get(&app, "/health").await.text(), "ok");
+
+    flag.store(false, Ordering::SeqCst);
+    assert_eq!(get(&app, "/about").await.text(), "about");
+}
+
+#[test]
+fn send_builders_can_be_moved_to_other_threads() {
+    fn assert_send<T: Send + Sync + Clone + 'static>(value: T) -> T {
+        value
+    }
+
+    let pages = assert_send(TeraPageSend::new("pages", |req: HttpRequest| {
+        let path = req.path().to_string();
+        async move {
+            rt::task::yield_now().await;
+            let mut context = Context::new();
+            context.insert("path", &path);
+            context
+        }
+    }));
+    let workers = (0..2)
+        .map(|_| {
+            let pages = pages.clone();
+            std::thread::spawn(move || {
+                rt::System::new().block_on(async move {
+                    let app = app(
+                        pages.page(),
+                        tera(&[("pages/index.html", "{{ path | safe }}")]),
+                    )
+                    .await;
+                    get(&app, "/").await.text().to_string()
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for worker in workers {
+        assert_eq!(worker.join().unwrap(), "/");
+    }
+}
+
+#[cfg(feature = "regex")]
+#[actix_web::test]
+async fn routes_select_templates_by_pattern() {
+    let page = TeraPage::static_only("pages")
+        .match_strategy(MatchStrategy::Regex(vec![(
+            Regex::new(r"^/docs/(?P<page>[\w-]+)$").unwrap(),
+            "pages/docs/$page.html".to_string(),
+        )]))
+        .ignore_pattern("_*");
+    let regex = app(
+        page,
+        tera(&[
+            ("pages/docs/intro.html", "intro {{ captures.page }}"),
+            ("pages/docs/_secret.html", "secret"),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&regex, "/docs/intro").await.text(), "intro intro");
+    assert_eq!(get(&regex, "/docs/_secret").await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn routes_select_templates_by_glob() {
+    let page = TeraPage::static_only("pages")
+        .match_strategy(MatchStrategy::Glob(vec![
+            ("/guide/*".to_string(), "pages/guide.html".to_string()),
+            ("/hidden/*".to_string(), "pages/_hidden.html".to_string()),
+        ]))
+        .ignore_pattern("_*");
+    let glob = app(
+        page,
+        tera(&[
+            ("pages/guide.html", "guide"),
+            ("pages/_hidden.html", "hidden"),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&glob, "/guide/any/depth").await.text(), "guide");
+    assert_eq!(get(&glob, "/hidden/page").await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn fallthroughs_report_the_candidates_tried() {
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let seen = reports.clone();
+    let page = TeraPage::static_only("pages").on_fallthrough(move |path, candidates| {
+        seen.lock()
+            .unwrap()
+            .push((path.to_string(), candidates.to_vec()));
+    });
+    let app = app(page, tera(&[("pages/about.html", "about")])).await;
+
+    assert_eq!(get(&app, "/about").await.text(), "about");
+    assert_eq!(get(&app, "/missing").await.text(), INNER);
+
+    let reports = reports.lock().unwrap();
+    assert_eq!(reports.len(), 1);
+    let (path, candidates) = &reports[0];
+    assert_eq!(path, "/missing");
+    assert!(candidates.contains(&"pages/missing.html".to_string()));
+    assert!(candidates.contains(&"pages/missing/index.html".to_string()));
+}
+
+#[actix_web::test]
+async fn dev_glob_picks_up_template_edits() {
+    let dir = env::temp_dir().join(format!("tera-page-dev-{}", std::process::id()));
+    fs::create_dir_all(dir.join("pages")).unwrap();
+    let template = dir.join("pages/about.html");
+    fs::write(&template, "before").unwrap();
+
+    let glob = format!("{}/**/*.html", dir.display());
+    let page = TeraPage::static_only("pages").dev_glob(&glob);
+    let app = app(page, tera(&[])).await;
+
+    assert_eq!(get(&app, "/about").await.text(), "before");
+    fs::write(&template, "after").unwrap();
+    assert_eq!(get(&app, "/about").await.text(), "after");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(feature = "actix-session")]
+#[actix_web::test]
+async fn flash_messages_are_shown_once() {
+    use actix_session::{storage::CookieSessionStore, Session, SessionMiddleware};
+    use actix_web::cookie::{Cookie, Key};
+
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera(&[(
+                "pages/done.html",
+                "{% for message in flash | default(value=[]) %}{{ message }}{% endfor %}done",
+            )])))
+            .wrap(TeraPage::static_only("pages").flash(true))
+            .wrap(SessionMiddleware::new(
+                CookieSessionStore::default(),
+                Key::generate(),
+            ))
+            .route(
+                "/save",
+                web::post().to(|session: Session| async move {
+                    session.insert(TeraPage::FLASH_KEY, ["Saved. "])?;
+                    Ok::<_, Error>(
+                        HttpResponse::SeeOther()
+                            .insert_header(("location", "/done"))
+                            .finish(),
+                    )
+                }),
+            ),
+    )
+    .await;
+    let session_cookie = |page: &Page| {
+        Cookie::parse_encoded(page.header("set-cookie").unwrap().to_string()).unwrap()
+    };
+
+    let saved = call(&app, TestRequest::post().uri("/save")).await;
+    assert_eq!(saved.status, StatusCode::SEE_OTHER);
+
+    let page = call(
+        &app,
+        TestRequest::get()
+            .uri("/done")
+            .cookie(session_cookie(&saved)),
+    )
+    .await;
+    assert_eq!(page.text(), "Saved. done");
+
+    let page = call(
+        &app,
+        TestRequest::get()
+            .uri("/done")
+            .cookie(session_cookie(&page)),
+    )
+    .await;
+    assert_eq!(page.text(), "done");
+}
+
+#[cfg(all(feature = "static-cache", feature = "test-util"))]
+#[actix_web::test]
+async fn head_requests_are_answered_from_the_cache() {
+    let body = "<h1>About</h1>";
+    let templates = [("pages/about.html", body)];
+    let page = TeraPage::static_only("pages")
+        .static_pages(&["/about"])
+        .warm_cache(&tera(&templates), &Context::new(), &["/about"]);
+    let metrics = page.metrics();
+    let app = app(page, tera(&templates)).await;
+    let warmed = metrics.template_renders();
+
+    // actix-web sends the sized body's length as `Content-Length` and drops the body itself.
+    let res = test::call_service(
+        &app,
+        TestRequest::default()
+            .method(Method::HEAD)
+            .uri("/about")
+            .to_request(),
+    )
+    .await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(
+        res.response().body().size(),
+        BodySize::Sized(body.len() as u64)
+    );
+    assert_eq!(metrics.template_renders(), warmed);
+}
+
+#[actix_web::test]
+async fn builder_sets_the_template_naming() {
+    let page = TeraPage::builder()
+        .prefix("/content/")
+        .extension("tera")
+        .index_name("_index")
+        .context_builder(|_| async {
+            let mut context = Context::new();
+            context.insert("title", "Docs");
+            context
+        })
+        .build()
+        .noindex(&["/drafts"]);
+    let app = app(
+        page,
+        tera(&[
+            ("content/docs/_index.tera", "{{ title }} index"),
+            ("content/docs/intro.tera", "{{ title }} intro"),
+            ("content/drafts/next.tera", "draft"),
+            ("pages/docs/intro.html", "wrong prefix"),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/docs").await.text(), "Docs index");
+    assert_eq!(get(&app, "/docs/intro").await.text(), "Docs intro");
+    let page = get(&app, "/drafts/next").await;
+    assert_eq!(page.text(), "draft");
+    assert!(page.header("x-robots-tag").is_some());
+}
+
+#[actix_web::test]
+async fn builder_sets_page_options() {
+    let mut globals = Context::new();
+    globals.insert("site", "Docs");
+    let page = TeraPage::builder()
+        .prefix("content")
+        .globals(globals)
+        .noindex(&["/drafts"])
+        .success_status(StatusCode::ACCEPTED)
+        .etag_fn(|_, _| Some("v1".to_string()))
+        .post_render_async(|body, _| async move { body.to_uppercase() })
+        .build();
+    let app = app(
+        page,
+        tera(&[("content/drafts/next.html", "{{ site }} draft")]),
+    )
+    .await;
+
+    let page = get(&app, "/drafts/next").await;
+    assert_eq!(page.status, StatusCode::ACCEPTED);
+    assert_eq!(page.text(), "DOCS DRAFT");
+    assert!(page.header("x-robots-tag").is_some());
+    assert!(page.header("etag").is_some());
+}
+
+#[actix_web::test]
+async fn matching_etags_skip_the_render() {
+    let builds = Arc::new(AtomicUsize::new(0));
+    let counter = builds.clone();
+    let page = TeraPage::new("pages", move |_| {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async { Context::new() }
+    })
+    .etag_fn(|template, _| Some(format!("v1-{}", template.len())));
+    let app = app(page, tera(&[("pages/about.html", "about")])).await;
+
+    let page = get(&app, "/about").await;
+    assert_eq!(page.text(), "about");
+    let etag = page.header("etag").unwrap().to_string();
+    assert_eq!(etag, "\"v1-16\"");
+    assert_eq!(builds.load(Ordering::SeqCst), 1);
+
+    let page = call(
+        &app,
+        TestRequest::get()
+            .uri("/about")
+            .insert_header(("if-none-match", etag.as_str())),
+    )
+    .await;
+    assert_eq!(page.status, StatusCode::NOT_MODIFIED);
+    assert!(page.body.is_empty());
+    assert_eq!(builds.load(Ordering::SeqCst), 1);
+
+    let stale = TestRequest::get()
+        .uri("/about")
+        .insert_header(("if-none-match", "\"v0\""));
+    assert_eq!(call(&app, stale).await.status, StatusCode::OK);
+    assert_eq!(builds.load(Ordering::SeqCst), 2);
+}
+
+#[actix_web::test]
+async fn json_errors_become_pages_for_html_clients() {
+    let page = TeraPage::static_only("pages");
+    let error_pages = page.with_error_handlers();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera(&[(
+                "pages/500.html",
+                "Something went wrong",
+            )])))
+            .wrap(page)
+            .wrap(error_pages)
+            .route(
+                "/api/items",
+                web::get().to(|| async {
+                    HttpResponse::InternalServerError()
+                        .content_type("application/json")
+                        .body(r#"{"error":"database unavailable"}"#)
+                }),
+            ),
+    )
+    .await;
+    let accepting = |accept| {
+        TestRequest::get()
+            .uri("/api/items")
+            .insert_header(("accept", accept))
+    };
+
+    let page = call(&app, accepting("text/html")).await;
+    assert_eq!(page.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(page.text(), "Something went wrong");
+    assert_eq!(
+        page.header("content-type"),
+        Some("text/html; charset=utf-8")
+    );
+
+    let page = call(&app, accepting("application/json")).await;
+    assert_eq!(page.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(page.text(), r#"{"error":"database unavailable"}"#);
+    assert_eq!(page.header("content-type"), Some("application/json"));
+}
+
+#[actix_web::test]
+async fn minified_pages_keep_preformatted_text() {
+    let page = TeraPage::static_only("pages").minify(true);
+    let app = app(
+        page,
+        tera(&[(
+            "pages/code.html",
+            "<main>\n\n    <p>Some   <em>code</em>:</p>  <!-- example -->\n    <pre>fn main() {\n    run();\n}</pre>\n</main>",
+        )]),
+    )
+    .await;
+
+    assert_eq!(
+        get(&app, "/code").await.text(),
+        "<main>\n<p>Some <em>code</em>:</p>\n<pre>fn main() {\n    run();\n}</pre>\n</main>"
+    );
+}
+
+#[actix_web::test]
+async fn sections_can_have_their_own_tera() {
+    let docs = Arc::new(tera(&[("pages/docs/intro.html", "docs tera")]));
+    let api_docs = Arc::new(tera(&[("pages/docs/api/intro.html", "api docs tera")]));
+    let page = TeraPage::static_only("pages").tera_for_prefix(HashMap::from([
+        ("/docs".to_string(), docs),
+        ("/docs/api/".to_string(), api_docs),
+    ]));
+    let app = app(
+        page,
+        tera(&[
+            ("pages/about.html", "default tera"),
+            ("pages/docs/intro.html", "shadowed"),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/docs/intro").await.text(), "docs tera");
+    assert_eq!(get(&app, "/docs/api/intro").await.text(), "api docs tera");
+    assert_eq!(get(&app, "/about").await.text(), "default tera");
+}
+
+#[actix_web::test]
+async fn templates_are_reloaded_on_request() {
+    let dir = env::temp_dir().join(format!("tera-page-reload-{}", std::process::id()));
+    fs::create_dir_all(dir.join("pages")).unwrap();
+    let template = dir.join("pages/about.html");
+    fs::write(&template, "v1").unwrap();
+
+    let tera = Tera::new(&format!("{}/**/*.html", dir.display())).unwrap();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(ArcSwap::from_pointee(tera)))
+            .wrap(TeraPage::static_only("pages").reloadable_tera())
+            .route("/_admin/reload", web::post().to(TeraPage::reload_handler)),
+    )
+    .await;
+    let reload = || call(&app, TestRequest::post().uri("/_admin/reload"));
+
+    assert_eq!(get(&app, "/about").await.text(), "v1");
+    fs::write(&template, "v2").unwrap();
+    assert_eq!(get(&app, "/about").await.text(), "v1");
+    assert_eq!(reload().await.status, StatusCode::NO_CONTENT);
+    assert_eq!(get(&app, "/about").await.text(), "v2");
+
+    fs::write(&template, "{{ unclosed").unwrap();
+    let failed = reload().await;
+    assert_eq!(failed.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(failed.text().starts_with("Failed to reload templates"));
+    assert_eq!(get(&app, "/about").await.text(), "v2");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[actix_web::test]
+async fn canonical_paths_follow_the_trailing_slash_policy() {
+    let templates = [
+        ("pages/blog/index.html", "{{ canonical | safe }}"),
+        ("pages/blog/post.html", "{{ canonical | safe }}"),
+    ];
+    let page = |slash| {
+        TeraPage::static_only("pages")
+            .index_canonical(slash)
+            .rewrite_path(|path| match path.strip_prefix("/v2") {
+                Some(rest) => Cow::Owned(rest.to_string()),
+                None => Cow::Borrowed(path),
+            })
+    };
+
+    let always = app(page(TrailingSlash::Always), tera(&templates)).await;
+    assert_eq!(get(&always, "/blog").await.text(), "/blog/");
+    assert_eq!(get(&always, "/v2//blog/").await.text(), "/blog/");
+    assert_eq!(get(&always, "/blog//post/").await.text(), "/blog/post");
+
+    let trim = app(page(TrailingSlash::Trim), tera(&templates)).await;
+    assert_eq!(get(&trim, "/blog/").await.text(), "/blog");
+    assert_eq!(get(&trim, "/v2//blog/").await.text(), "/blog");
+    assert_eq!(get(&trim, "/v2/blog//post").await.text(), "/blog/post");
+}
+
+#[actix_web::test]
+async fn seo_files_are_served_with_their_content_types() {
+    let page = TeraPage::new("pages", |_| async {
+        let mut context = Context::new();
+        context.insert("pages", &["/", "/about"]);
+        context
+    })
+    .seo_files(true);
+    let app = app(
+        page,
+        tera(&[
+            ("pages/robots.txt", "User-agent: *\nSitemap: /sitemap.xml"),
+            (
+                "pages/sitemap.xml",
+                "<urlset>{% for page in pages %}<url><loc>{{ page | safe }}</loc></url>{% endfor %}</urlset>",
+            ),
+        ]),
+    )
+    .await;
+
+    let robots = get(&app, "/robots.txt").await;
+    assert_eq!(robots.text(), "User-agent: *\nSitemap: /sitemap.xml");
+    assert_eq!(
+        robots.header("content-type"),
+        Some("text/plain; charset=utf-8")
+    );
+
+    let sitemap = get(&app, "/sitemap.xml").await;
+    assert_eq!(
+        sitemap.text(),
+        "<urlset><url><loc>/</loc></url><url><loc>/about</loc></url></urlset>"
+    );
+    assert_eq!(sitemap.header("content-type"), Some("application/xml"));
+}
+
+#[actix_web::test]
+async fn rewritten_paths_only_affect_matching() {
+    let page = TeraPage::static_only("pages").rewrite_path(|path| match path.strip_prefix("/v2") {
+        Some(rest) => Cow::Owned(rest.to_string()),
+        None => Cow::Borrowed(path),
+    });
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera(&[("pages/about.html", "about")])))
+            .wrap(page)
+            .default_service(web::to(|req: HttpRequest| async move {
+                HttpResponse::NotFound().body(req.path().to_string())
+            })),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/v2/about").await.text(), "about");
+    assert_eq!(get(&app, "/about").await.text(), "about");
+    assert_eq!(get(&app, "/v2/missing").await.text(), "/v2/missing");
+}
+
+#[actix_web::test]
+async fn serializable_values_become_the_context() {
+    #[derive(serde::Serialize)]
+    struct About {
+        title: &'static str,
+        team: Vec<&'static str>,
+    }
+
+    let page = TeraPage::new_serialize("pages", |_| async {
+        About {
+            title: "About",
+            team: vec!["Ada", "Grace"],
+        }
+    });
+    let about = app(
+        page,
+        tera(&[(
+            "pages/about.html",
+            "{{ title }}: {{ team | join(sep=\", \") }}",
+        )]),
+    )
+    .await;
+
+    assert_eq!(get(&about, "/about").await.text(), "About: Ada, Grace");
+
+    let page = TeraPage::new_serialize("pages", |_| async { vec!["not", "a", "map"] });
+    let app = app(page, tera(&[("pages/about.html", "about")])).await;
+    assert_eq!(
+        get(&app, "/about").await.status,
+        StatusCode::INTERNAL_SERVER_ERROR
+    );
+}
+
+#[actix_web::test]
+async fn not_found_bodies_depend_on_accept() {
+    let page = TeraPage::static_only("pages")
+        .not_found_body("application/json", r#"{"error":"not found"}"#);
+    let error_pages = page.with_error_handlers();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera(&[("pages/404.html", "<h1>Not found</h1>")])))
+            .wrap(page)
+            .wrap(error_pages)
+            .configure(TeraPage::configure),
+    )
+    .await;
+    let accepting = |accept| {
+        TestRequest::get()
+            .uri("/missing")
+            .insert_header(("accept", accept))
+    };
+
+    let page = call(&app, accepting("text/html")).await;
+    assert_eq!(page.status, StatusCode::NOT_FOUND);
+    assert_eq!(page.text(), "<h1>Not found</h1>");
+
+    let page = call(&app, accepting("application/json")).await;
+    assert_eq!(page.status, StatusCode::NOT_FOUND);
+    assert_eq!(page.text(), r#"{"error":"not found"}"#);
+    assert_eq!(page.header("content-type"), Some("application/json"));
+}
+
+#[actix_web::test]
+async fn build_info_is_injected() {
+    let templates = [(
+        "pages/index.html",
+        "v{{ build.version }} ({{ build.git_sha | default(value='dev') }})",
+    )];
+
+    let page = TeraPage::static_only("pages").build_info(build_info!());
+    let compiled = app(page, tera(&templates)).await;
+    let expected = format!("v{} (", env!("CARGO_PKG_VERSION"));
+    assert!(get(&compiled, "/").await.text().starts_with(&expected));
+
+    let page = TeraPage::static_only("pages").build_info(BuildInfo {
+        version: "1.2.3".to_string(),
+        git_sha: Some("abc1234".to_string()),
+        built_at: None,
+    });
+    let given = app(page, tera(&templates)).await;
+    assert_eq!(get(&given, "/").await.text(), "v1.2.3 (abc1234)");
+}
+
+#[actix_web::test]
+async fn template_index_is_authoritative() {
+    let index = HashSet::from(["pages/about.html".to_string()]);
+    let page = TeraPage::static_only("pages").template_index(Arc::new(index));
+    let app = app(
+        page,
+        tera(&[("pages/about.html", "about"), ("pages/team.html", "team")]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/about").await.text(), "about");
+    assert_eq!(get(&app, "/team").await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn pages_set_the_returned_cookies() {
+    use actix_web::cookie::{Cookie, SameSite};
+
+    let page = TeraPage::static_only("pages").set_cookies(|_| {
+        vec![
+            Cookie::new("csrf", "token"),
+            Cookie::build("theme", "dark")
+                .same_site(SameSite::Strict)
+                .finish(),
+        ]
+    });
+    let app = app(page, tera(&[("pages/index.html", "index")])).await;
+
+    let page = get(&app, "/").await;
+    let cookies = page
+        .header_values("set-cookie")
+        .into_iter()
+        .map(|c| Cookie::parse(c.to_string()).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(cookies.len(), 2);
+    assert_eq!((cookies[0].name(), cookies[0].value()), ("csrf", "token"));
+    assert_eq!(cookies[0].same_site(), Some(SameSite::Lax));
+    assert_eq!(cookies[0].secure(), None);
+    assert_eq!(cookies[1].same_site(), Some(SameSite::Strict));
+
+    let https = TestRequest::get().uri("https://example.com/");
+    let page = call(&app, https).await;
+    let csrf = Cookie::parse(page.header_values("set-cookie")[0].to_string()).unwrap();
+    assert_eq!(csrf.secure(), Some(true));
+}
+
+#[actix_web::test]
+async fn unavailable_outcomes_ask_clients_to_retry() {
+    let page = TeraPage::builder()
+        .outcome_builder(|req: HttpRequest| {
+            let overloaded = req.query_string() == "overloaded";
+            async move {
+                if overloaded {
+                    PageOutcome::Unavailable {
+                        retry_after: Duration::from_secs(30),
+                    }
+                } else {
+                    PageOutcome::Render(Context::new())
+                }
+            }
+        })
+        .build();
+    let app = app(page, tera(&[("pages/index.html", "index")])).await;
+
+    assert_eq!(get(&app, "/").await.text(), "index");
+    let page = get(&app, "/?overloaded").await;
+    assert_eq!(page.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(page.header("retry-after"), Some("30"));
+}
+
+#[actix_web::test]
+async fn active_tera_can_be_switched() {
+    let active = Arc::new(ArcSwap::from_pointee(tera(&[("pages/index.html", "blue")])));
+    let page = TeraPage::static_only("pages").active_tera(active.clone());
+    // Static pages rendered with the old `Tera` are dropped when it's replaced.
+    #[cfg(feature = "static-cache")]
+    let page = page.static_pages(&["/"]);
+    // No `Tera` is registered as app data: the active one is used instead.
+    let app = test::init_service(App::new().wrap(page).configure(TeraPage::configure)).await;
+
+    assert_eq!(get(&app, "/").await.text(), "blue");
+    active.store(Arc::new(tera(&[("pages/index.html", "green")])));
+    assert_eq!(get(&app, "/").await.text(), "green");
+}
+
+#[cfg(feature = "static-cache")]
+#[actix_web::test]
+async fn pages_rendered_with_a_replaced_tera_are_not_cached() {
+    let active = Arc::new(ArcSwap::from_pointee(tera(&[("pages/index.html", "blue")])));
+    let page = TeraPage::new("pages", |req: HttpRequest| {
+        let slow = req.headers().contains_key("x-slow");
+        async move {
+            if slow {
+                rt::time::sleep(Duration::from_millis(50)).await;
+            }
+            Context::new()
+        }
+    })
+    .active_tera(active.clone())
+    .static_pages(&["/"]);
+    let app = test::init_service(App::new().wrap(page)).await;
+
+    let slow = call(
+        &app,
+        TestRequest::get().uri("/").insert_header(("x-slow", "1")),
+    );
+    let switched = async {
+        active.store(Arc::new(tera(&[("pages/index.html", "green")])));
+        get(&app, "/").await
+    };
+    let (slow, switched) = join!(slow, switched);
+    assert_eq!(slow.text(), "blue");
+    assert_eq!(switched.text(), "green");
+    assert_eq!(get(&app, "/").await.text(), "green");
+}
+
+#[actix_web::test]
+async fn prefixes_are_chosen_by_environment() {
+    let templates = [
+        ("pages/index.html", "production"),
+        ("pages-staging/index.html", "staging"),
+    ];
+    let prefixes = || {
+        HashMap::from([
+            ("staging".to_string(), "pages-staging".to_string()),
+            ("production".to_string(), "pages".to_string()),
+            ("empty".to_string(), "pages-empty".to_string()),
+        ])
+    };
+    let for_env = |environment| {
+        let _env = ENV.lock().unwrap();
+        env::set_var("TERA_PAGE_ENV", environment);
+        let page = TeraPage::static_only("pages").prefix_for_env(&tera(&templates), prefixes());
+        env::remove_var("TERA_PAGE_ENV");
+        page
+    };
+
+    let staging = app(for_env("staging").unwrap(), tera(&templates)).await;
+    assert_eq!(get(&staging, "/").await.text(), "staging");
+    let production = app(for_env("production").unwrap(), tera(&templates)).await;
+    assert_eq!(get(&production, "/").await.text(), "production");
+
+    assert!(matches!(for_env("qa"), Err(ConfigError::InvalidEnv { .. })));
+    assert!(matches!(
+        for_env("empty"),
+        Err(ConfigError::EmptyPrefix { .. })
+    ));
+}
+
+#[actix_web::test]
+async fn post_render_hooks_replace_the_body() {
+    let page = TeraPage::static_only("pages").post_render_async(|body, req| {
+        let path = req.path().to_string();
+        async move {
+            rt::task::yield_now().await;
+            body.replace("</body>", &format!("<!-- {path} --></body>"))
+        }
+    });
+    let app = app(page, tera(&[("pages/about.html", "<body>about</body>")])).await;
+
+    assert_eq!(
+        get(&app, "/about").await.text(),
+        "<body>about<!-- /about --></body>"
+    );
+}
+
+#[actix_web::test]
+async fn injection_keys_can_be_renamed() {
+    let page = TeraPage::new("pages", |_| async {
+        let mut context = Context::new();
+        context.insert("path", "the builder's own value");
+        context
+    })
+    .inject_path(true)
+    .injection_keys(InjectionKeys {
+        path: "request_path".to_string(),
+        ..InjectionKeys::default()
+    });
+    let app = app(
+        page,
+        tera(&[("pages/about.html", "{{ path }} / {{ request_path | safe }}")]),
+    )
+    .await;
+
+    assert_eq!(
+        get(&app, "/about").await.text(),
+        "the builder&#x27;s own value / /about"
+    );
+}
+
+#[cfg(feature = "regex")]
+#[actix_web::test]
+async fn builder_values_win_over_every_injected_key() {
+    let page = TeraPage::new("pages", |_| async {
+        let mut context = Context::new();
+        for key in ["request_id", "captures", "device", "template_source"] {
+            context.insert(key, &format!("own {}", key));
+        }
+        context
+    })
+    .request_id("x-request-id")
+    .match_strategy(MatchStrategy::Regex(vec![(
+        Regex::new(r"^/posts/(?P<id>\d+)$").unwrap(),
+        "pages/post.html".to_string(),
+    )]))
+    .device_variant(|_| Some("mobile".to_string()))
+    .inject_template_source(true);
+    let app = app(
+        page,
+        tera(&[(
+            "pages/post.html",
+            "{{ request_id }}, {{ captures }}, {{ device }}, {{ template_source }}",
+        )]),
+    )
+    .await;
+
+    assert_eq!(
+        get(&app, "/posts/7").await.text(),
+        "own request_id, own captures, own device, own template_source"
+    );
+}
+
+#[actix_web::test]
+async fn handlers_render_templates_like_the_middleware() {
+    let page = || {
+        TeraPage::new("pages", |_| async {
+            let mut context = Context::new();
+            context.insert("title", "Welcome");
+            context
+        })
+        .preload_map(HashMap::from([(
+            "pages/welcome.html".to_string(),
+            vec!["/welcome.css".to_string()],
+        )]))
+    };
+    let tera = tera(&[("pages/welcome.html", "<h1>{{ title }}</h1>")]);
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera.clone()))
+            .app_data(Data::new(page()))
+            .wrap(page())
+            .route(
+                "/start",
+                web::get().to(
+                    |page: Data<TeraPage>, tera: Data<Tera>, req: HttpRequest| async move {
+                        page.render_template(tera.into_inner(), &req, "pages/welcome.html")
+                            .await
+                    },
+                ),
+            ),
+    )
+    .await;
+
+    let rendered = get(&app, "/start").await;
+    let matched = get(&app, "/welcome").await;
+    assert_eq!(rendered.status, StatusCode::OK);
+    assert_eq!(rendered.text(), "<h1>Welcome</h1>");
+    assert_eq!(rendered.body, matched.body);
+    assert_eq!(
+        rendered.header("content-type"),
+        matched.header("content-type")
+    );
+    assert_eq!(
+        rendered.header("link"),
+        Some("</welcome.css>; rel=preload; as=style")
+    );
+    assert_eq!(rendered.header("link"), matched.header("link"));
+}
+
+#[actix_web::test]
+async fn aliases_redirect_permanently() {
+    let aliases = || HashMap::from([("/old-about".to_string(), "/about".to_string())]);
+    let templates = [("pages/about.html", "about")];
+
+    let preserving = app(
+        TeraPage::static_only("pages").aliases(aliases()),
+        tera(&templates),
+    )
+    .await;
+    let page = get(&preserving, "/old-about?ref=mail").await;
+    assert_eq!(page.status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(page.header("location"), Some("/about?ref=mail"));
+
+    let dropping = app(
+        TeraPage::static_only("pages")
+            .aliases(aliases())
+            .preserve_query_on_redirect(false),
+        tera(&templates),
+    )
+    .await;
+    let page = get(&dropping, "/old-about?ref=mail").await;
+    assert_eq!(page.status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(page.header("location"), Some("/about"));
+}
+
+#[actix_web::test]
+async fn private_pages_are_kept_out_of_shared_caches() {
+    let page = TeraPage::new("pages", |req: HttpRequest| {
+        let signed_in = req.headers().contains_key("authorization");
+        async move {
+            let mut context = Context::new();
+            context.insert(TeraPage::PRIVATE_KEY, &signed_in);
+            context
+        }
+    })
+    .auto_private(true);
+    let app = app(page, tera(&[("pages/account.html", "account")])).await;
+
+    assert!(get(&app, "/account")
+        .await
+        .header("cache-control")
+        .is_none());
+    let signed_in = TestRequest::get()
+        .uri("/account")
+        .insert_header(("authorization", "Bearer token"));
+    assert_eq!(
+        call(&app, signed_in).await.header("cache-control"),
+        Some("private, no-store")
+    );
+}
+
+#[actix_web::test]
+async fn asset_extensions_fall_through() {
+    let templates = [
+        ("pages/logo.png.html", "oddly matched"),
+        ("pages/feed.rss.html", "feed"),
+    ];
+    let page = TeraPage::static_only("pages").match_strategy(MatchStrategy::Glob(vec![(
+        "/*".to_string(),
+        "pages/logo.png.html".to_string(),
+    )]));
+    let defaults = app(page, tera(&templates)).await;
+    assert_eq!(get(&defaults, "/logo.png").await.text(), INNER);
+    assert_eq!(get(&defaults, "/LOGO.PNG").await.text(), INNER);
+
+    let page = TeraPage::static_only("pages").skip_extensions(&[".rss"]);
+    let configured = app(page, tera(&templates)).await;
+    assert_eq!(get(&configured, "/feed.rss").await.text(), INNER);
+    assert_eq!(get(&configured, "/logo.png").await.text(), "oddly matched");
+}
+
+#[cfg(feature = "test-util")]
+#[actix_web::test]
+async fn render_counter_counts_each_render() {
+    let page = TeraPage::static_only("pages").compose(&["header.html", TeraPage::COMPOSED_PAGE]);
+    let metrics = page.metrics();
+    let app = app(
+        page,
+        tera(&[("header.html", "header "), ("pages/about.html", "about")]),
+    )
+    .await;
+    assert_eq!(metrics.template_renders(), 0);
+
+    assert_eq!(get(&app, "/about").await.text(), "header about");
+    assert_eq!(metrics.template_renders(), 1);
+
+    join!(
+        get(&app, "/about"),
+        get(&app, "/about"),
+        get(&app, "/missing")
+    );
+    assert_eq!(metrics.template_renders(), 3);
+}
+
+#[actix_web::test]
+async fn options_requests_list_the_allowed_methods() {
+    let options = |uri| TestRequest::default().method(Method::OPTIONS).uri(uri);
+    let templates = [("pages/about.html", "about")];
+
+    let handling = app(
+        TeraPage::static_only("pages").handle_options(true),
+        tera(&templates),
+    )
+    .await;
+    let page = call(&handling, options("/about")).await;
+    assert_eq!(page.status, StatusCode::NO_CONTENT);
+    assert_eq!(page.header("allow"), Some("GET, HEAD, OPTIONS"));
+    assert_eq!(call(&handling, options("/missing")).await.text(), INNER);
+
+    let passing = app(TeraPage::static_only("pages"), tera(&templates)).await;
+    assert_eq!(call(&passing, options("/about")).await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn mobile_devices_get_the_mobile_template() {
+    let page = TeraPage::static_only("pages").device_variant(|req| {
+        let user_agent = req.headers().get("user-agent")?.to_str().ok()?;
+        user_agent.contains("Mobile").then(|| "mobile".to_string())
+    });
+    let app = app(
+        page,
+        tera(&[
+            ("pages/about.html", "desktop"),
+            ("pages/about.mobile.html", "{{ device }}"),
+        ]),
+    )
+    .await;
+    let with_agent = |agent| {
+        TestRequest::get()
+            .uri("/about")
+            .insert_header(("user-agent", agent))
+    };
+
+    let page = call(&app, with_agent("Mozilla/5.0 (iPhone) Mobile/15E148")).await;
+    assert_eq!(page.text(), "mobile");
+    assert!(page.header_values("vary").contains(&"user-agent"));
+
+    let page = call(&app, with_agent("Mozilla/5.0 (X11; Linux x86_64)")).await;
+    assert_eq!(page.text(), "desktop");
+    assert!(page.header_values("vary").contains(&"user-agent"));
+}
+
+#[cfg(feature = "static-cache")]
+#[actix_web::test]
+async fn failed_builds_serve_the_last_good_render() {
+    struct Token(&'static str);
+
+    impl AsRef<str> for Token {
+        fn as_ref(&self) -> &str {
+            self.0
+        }
+    }
+
+    let page = TeraPage::builder()
+        .prefix("pages")
+        .try_context_builder(|req: HttpRequest| {
+            let failing = req.headers().contains_key("x-fail");
+            async move {
+                if failing {
+                    Err(std::io::Error::other("database is down"))
+                } else {
+                    Ok(Context::new())
+                }
+            }
+        })
+        .build()
+        .serve_stale_on_error(true)
+        .inject_csrf::<Token>();
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera(&[(
+                "pages/about.html",
+                "about {{ csrf_token | default(value='nobody') }}",
+            )])))
+            .wrap(page)
+            .wrap_fn(|req, srv| {
+                if req.headers().contains_key("x-csrf") {
+                    req.extensions_mut().insert(Token("secret"));
+                }
+                srv.call(req)
+            }),
+    )
+    .await;
+    let with_header = |name| TestRequest::get().uri("/about").insert_header((name, "1"));
+
+    assert_eq!(
+        call(&app, with_header("x-csrf")).await.text(),
+        "about secret"
+    );
+    let page = call(&app, with_header("x-fail")).await;
+    assert_eq!(page.status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    assert_eq!(get(&app, "/about").await.text(), "about nobody");
+    let page = call(&app, with_header("x-fail")).await;
+    assert_eq!(page.status, StatusCode::OK);
+    assert_eq!(page.text(), "about nobody");
+    assert_eq!(page.header("warning"), Some("110 - \"Response is Stale\""));
+}
+
+#[cfg(all(feature = "static-cache", feature = "regex"))]
+#[actix_web::test]
+async fn stale_pages_are_kept_per_path() {
+    let page = TeraPage::builder()
+        .prefix("pages")
+        .try_context_builder(|req: HttpRequest| {
+            let failing = req.headers().contains_key("x-fail");
+            async move {
+                if failing {
+                    Err(std::io::Error::other("database is down"))
+                } else {
+                    Ok(Context::new())
+                }
+            }
+        })
+        .build()
+        .match_strategy(MatchStrategy::Regex(vec![(
+            Regex::new(r"^/blog/(?P<id>\d+)$").unwrap(),
+            "pages/post.html".to_string(),
+        )]))
+        .serve_stale_on_error(true);
+    let app = app(page, tera(&[("pages/post.html", "post {{ captures.id }}")])).await;
+    let failing = |uri| TestRequest::get().uri(uri).insert_header(("x-fail", "1"));
+
+    assert_eq!(get(&app, "/blog/1").await.text(), "post 1");
+    let page = call(&app, failing("/blog/2")).await;
+    assert_eq!(page.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(call(&app, failing("/blog/1")).await.text(), "post 1");
+}
+
+#[actix_web::test]
+async fn prefix_paths_are_handled_as_configured() {
+    let templates = [
+        ("pages/index.html", "home"),
+        ("pages/pages.html", "pages page"),
+    ];
+    let serve = |behavior| async move {
+        let page = TeraPage::static_only("pages").prefix_path_behavior(behavior);
+        let app = app(page, tera(&templates)).await;
+        get(&app, "/pages").await
+    };
+
+    assert_eq!(serve(PrefixPath::Page).await.text(), "pages page");
+    assert_eq!(serve(PrefixPath::Index).await.text(), "home");
+    assert_eq!(serve(PrefixPath::FallThrough).await.text(), INNER);
+    let page = serve(PrefixPath::NotFound).await;
+    assert_eq!(page.status, StatusCode::NOT_FOUND);
+    assert_ne!(page.text(), INNER);
+}
+
+#[actix_web::test]
+async fn event_streams_are_sent_unbuffered() {
+    let tera = tera(&[("pages/events.sse", "data: {{ 1 + 1 }}\n\n")]);
+    let app = app(TeraPage::static_only("pages"), tera).await;
+
+    let res = test::call_service(&app, TestRequest::get().uri("/events.sse").to_request()).await;
+    assert_eq!(res.response().body().size(), BodySize::Stream);
+    let headers = res.headers();
+    assert_eq!(headers.get("content-type").unwrap(), "text/event-stream");
+    assert_eq!(headers.get("x-accel-buffering").unwrap(), "no");
+    assert_eq!(test::read_body(res).await, "data: 2\n\n");
+}
+
+#[actix_web::test]
+async fn csrf_tokens_are_injected_into_forms() {
+    struct CsrfToken(String);
+
+    impl AsRef<str> for CsrfToken {
+        fn as_ref(&self) -> &str {
+            &self.0
+        }
+    }
+
+    let page = TeraPage::new("pages", |req: HttpRequest| async move {
+        let mut context = Context::new();
+        if req.path() == "/preset" {
+            context.insert("csrf_token", "preset");
+        }
+        context
+    })
+    .inject_csrf::<CsrfToken>();
+    let form = r#"<input type="hidden" name="csrf" value="{{ csrf_token }}">"#;
+    let app = test::init_service(
+        App::new()
+            .app_data(Data::new(tera(&[
+                ("pages/form.html", form),
+                ("pages/preset.html", form),
+            ])))
+            .wrap(page)
+            .wrap_fn(|req, srv| {
+                req.extensions_mut().insert(CsrfToken("a1b2c3".to_string()));
+                srv.call(req)
+            }),
+    )
+    .await;
+
+    assert_eq!(
+        get(&app, "/form").await.text(),
+        r#"<input type="hidden" name="csrf" value="a1b2c3">"#
+    );
+    assert_eq!(
+        get(&app, "/preset").await.text(),
+        r#"<input type="hidden" name="csrf" value="preset">"#
+    );
+}
+
+#[actix_web::test]
+async fn critical_failures_get_the_static_error_page() {
+    const OOPS: &str = "<h1>Something went wrong</h1>";
+    const BUSY: &str = "<h1>Try again shortly</h1>";
+    let page = || {
+        TeraPage::static_only("pages")
+            .static_error_page(StatusCode::INTERNAL_SERVER_ERROR, OOPS)
+            .static_error_page(StatusCode::SERVICE_UNAVAILABLE, BUSY)
+    };
+
+    let without_tera = test::init_service(App::new().wrap(page())).await;
+    let res = get(&without_tera, "/about").await;
+    assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(res.text(), OOPS);
+
+    let ambiguous = app(
+        page().on_ambiguous(Ambiguity::Error),
+        tera(&[("pages/foo.html", "foo"), ("pages/foo/index.html", "index")]),
+    )
+    .await;
+    let res = get(&ambiguous, "/foo").await;
+    assert_eq!(res.status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(res.text(), OOPS);
+
+    let slow = "{% for i in range(end=2000000) %}{% endfor %}slow";
+    let timing_out = app(
+        page().render_timeout(Duration::from_millis(1)),
+        tera(&[("pages/slow.html", slow)]),
+    )
+    .await;
+    let res = get(&timing_out, "/slow").await;
+    assert_eq!(res.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.text(), BUSY);
+}
+
+#[cfg(feature = "static-cache")]
+#[actix_web::test]
+async fn stale_pages_are_served_while_revalidating() {
+    let renders = Arc::new(AtomicUsize::new(0));
+    let counter = renders.clone();
+    let page = TeraPage::new("pages", move |_| {
+        let render = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            let mut context = Context::new();
+            context.insert("render", &render);
+            context
+        }
+    })
+    .static_pages(&["/news"])
+    .swr(Duration::from_millis(50), Duration::from_secs(60));
+    let app = app(page, tera(&[("pages/news.html", "render {{ render }}")])).await;
+
+    let page = get(&app, "/news").await;
+    assert_eq!(page.text(), "render 1");
+    assert!(page
+        .header("cache-control")
+        .unwrap()
+        .contains("stale-while-revalidate=60"));
+
+    rt::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(get(&app, "/news").await.text(), "render 1");
+    rt::time::sleep(Duration::from_millis(20)).await;
+    assert_eq!(renders.load(Ordering::SeqCst), 2);
+    assert_eq!(get(&app, "/news").await.text(), "render 2");
+}
+
+#[cfg(feature = "static-cache")]
+#[actix_web::test]
+async fn revalidated_pages_are_checked_like_fresh_ones() {
+    let renders = Arc::new(AtomicUsize::new(0));
+    let counter = renders.clone();
+    let page = TeraPage::new("pages", move |_| {
+        let render = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        async move {
+            let mut context = Context::new();
+            // The first re-render is too large, the second one private.
+            let body = if render == 2 {
+                "x".repeat(100)
+            } else {
+                "ok".to_string()
+            };
+            context.insert("body", &body);
+            context.insert(TeraPage::PRIVATE_KEY, &(render == 3));
+            context
+        }
+    })
+    .static_pages(&["/news"])
+    .max_body_size(50)
+    .auto_private(true)
+    .swr(Duration::from_millis(50), Duration::from_secs(60));
+    let app = app(page, tera(&[("pages/news.html", "{{ body }}")])).await;
+    assert_eq!(get(&app, "/news").await.text(), "ok");
+
+    rt::time::sleep(Duration::from_millis(100)).await;
+    for render in [2, 3] {
+        assert_eq!(get(&app, "/news").await.text(), "ok");
+        rt::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(renders.load(Ordering::SeqCst), render);
+    }
+    assert_eq!(get(&app, "/news").await.text(), "ok");
+}
+
+#[actix_web::test]
+async fn context_gates_veto_empty_listings() {
+    let page = TeraPage::new("pages", |req: HttpRequest| async move {
+        let posts: Vec<&str> = match req.path() {
+            "/blog" => vec!["hello", "again"],
+            _ => vec![],
+        };
+        let mut context = Context::new();
+        context.insert("posts", &posts);
+        context
+    })
+    .context_gate(|context, template| {
+        let empty = context.get("posts") == Some(&Value::Array(vec![]));
+        match template {
+            "pages/archive.html" if empty => PageOutcome::FallThrough,
+            _ if empty => PageOutcome::Status(StatusCode::NOT_FOUND),
+            _ => PageOutcome::Render(context.clone()),
+        }
+    });
+    let listing = "{{ posts | join(sep=', ') }}";
+    let app = app(
+        page,
+        tera(&[
+            ("pages/blog.html", listing),
+            ("pages/drafts.html", listing),
+            ("pages/archive.html", listing),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/blog").await.text(), "hello, again");
+    let page = get(&app, "/drafts").await;
+    assert_eq!(page.status, StatusCode::NOT_FOUND);
+    assert_eq!(page.text(), "");
+    assert_eq!(get(&app, "/archive").await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn themes_are_tried_in_order() {
+    let page = TeraPage::static_only("pages").theme_chain(|req| {
+        let tenant = req.headers().get("x-tenant").and_then(|t| t.to_str().ok());
+        tenant
+            .into_iter()
+            .map(String::from)
+            .chain(["default".to_string(), "..".to_string()])
+            .collect()
+    });
+    let app = app(
+        page,
+        tera(&[
+            ("pages/acme/about.html", "acme about"),
+            ("pages/default/about.html", "default about"),
+            ("pages/default/contact.html", "default contact"),
+            ("pages/pricing.html", "plain pricing"),
+            ("secret.html", "secret"),
+        ]),
+    )
+    .await;
+    let tenant = |uri| {
+        TestRequest::get()
+            .uri(uri)
+            .insert_header(("x-tenant", "acme"))
+    };
+
+    assert_eq!(call(&app, tenant("/about")).await.text(), "acme about");
+    assert_eq!(get(&app, "/about").await.text(), "default about");
+    assert_eq!(
+        call(&app, tenant("/contact")).await.text(),
+        "default contact"
+    );
+    assert_eq!(call(&app, tenant("/pricing")).await.text(), "plain pricing");
+    assert_eq!(call(&app, tenant("/secret")).await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn redirects_keep_the_query_unless_disabled() {
+    let templates = [
+        ("pages/about.html", "about"),
+        ("pages/docs/intro.html", "intro"),
+    ];
+    let page = |preserve| {
+        TeraPage::static_only("pages")
+            .aliases(HashMap::from([(
+                "/about-us".to_string(),
+                "/about".to_string(),
+            )]))
+            .redirect_collapsed_slashes(true)
+            .preserve_query_on_redirect(preserve)
+    };
+
+    let preserving = app(page(true), tera(&templates)).await;
+    let alias = get(&preserving, "/about-us?ref=mail").await;
+    assert_eq!(alias.status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(alias.header("location"), Some("/about?ref=mail"));
+    let collapsed = get(&preserving, "/docs//intro?page=2").await;
+    assert_eq!(collapsed.status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(collapsed.header("location"), Some("/docs/intro?page=2"));
+
+    let dropping = app(page(false), tera(&templates)).await;
+    let alias = get(&dropping, "/about-us?ref=mail").await;
+    assert_eq!(alias.header("location"), Some("/about"));
+    let collapsed = get(&dropping, "/docs//intro?page=2").await;
+    assert_eq!(collapsed.header("location"), Some("/docs/intro"));
+}
+
+#[actix_web::test]
+async fn paths_deeper_than_max_depth_fall_through() {
+    let app = app(
+        TeraPage::static_only("pages").max_depth(2),
+        tera(&[
+            ("pages/docs/intro.html", "intro"),
+            ("pages/docs/guide/setup.html", "setup"),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/docs/intro").await.text(), "intro");
+    assert_eq!(get(&app, "/docs/intro/").await.text(), "intro");
+    assert_eq!(get(&app, "/docs/guide/setup").await.text(), INNER);
+}
+
+#[cfg(feature = "lab")]
+#[actix_web::test]
+async fn lab_html_keeps_the_page_status_and_headers() {
+    let page = TeraPage::static_only("pages")
+        .lab_html(true)
+        .success_status(StatusCode::CREATED)
+        .noindex(&["/thanks"]);
+    let app = app(page, tera(&[("pages/thanks.html", "<p>Thanks!</p>")])).await;
+
+    let page = get(&app, "/thanks").await;
+    assert_eq!(page.status, StatusCode::CREATED);
+    assert_eq!(
+        page.header("content-type"),
+        Some("text/html; charset=utf-8")
+    );
+    assert_eq!(page.header("x-robots-tag"), Some("noindex, nofollow"));
+    assert_eq!(page.text(), "<p>Thanks!</p>");
+}
+
+#[actix_web::test]
+async fn hosts_get_their_own_templates() {
+    let app = app(
+        TeraPage::static_only("pages").host_namespacing(true),
+        tera(&[
+            ("pages/example.com/about.html", "example about"),
+            ("pages/ex_ample.com/about.html", "underscored about"),
+            ("pages/about.html", "shared about"),
+        ]),
+    )
+    .await;
+    let from_host = |host| {
+        TestRequest::get()
+            .uri("/about")
+            .insert_header(("host", host))
+    };
+
+    assert_eq!(
+        call(&app, from_host("example.com:8080")).await.text(),
+        "example about"
+    );
+    assert_eq!(
+        call(&app, from_host("other.org")).await.text(),
+        "shared about"
+    );
+    assert_eq!(
+        call(&app, from_host("ex_ample.com")).await.text(),
+        "shared about"
+    );
+}
+
+#[actix_web::test]
+async fn loggers_receive_every_event() {
+    #[derive(Default)]
+    struct Events(Mutex<Vec<String>>);
+
+    impl Events {
+        fn push(&self, event: String) {
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    impl TeraPageLogger for Events {
+        fn candidates(&self, path: &str, candidates: &[String]) {
+            self.push(format!("candidates {} {}", path, candidates.join(",")));
+        }
+
+        fn matched(&self, path: &str, template: &str) {
+            self.push(format!("matched {} {}", path, template));
+        }
+
+        fn fallthrough(&self, path: &str) {
+            self.push(format!("fallthrough {}", path));
+        }
+
+        fn render_error(&self, template: &str, _message: &str) {
+            self.push(format!("render_error {}", template));
+        }
+    }
+
+    let events = Arc::new(Events::default());
+    let page = TeraPage::static_only("pages").logger(events.clone());
+    let app = app(
+        page,
+        tera(&[
+            ("pages/about.html", "about"),
+            ("pages/broken.html", "{{ missing }}"),
+        ]),
+    )
+    .await;
+
+    get(&app, "/about").await;
+    get(&app, "/missing").await;
+    get(&app, "/broken").await;
+
+    let events = events.0.lock().unwrap();
+    let of = |kind: &str| {
+        events
+            .iter()
+            .filter(|e| e.starts_with(kind))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let candidates = of("candidates /about ");
+    assert_eq!(candidates.len(), 1);
+    assert!(
+        candidates[0].contains("pages/about.html"),
+        "{}",
+        candidates[0]
+    );
+    assert_eq!(
+        of("matched"),
+        [
+            "matched /about pages/about.html",
+            "matched /broken pages/broken.html"
+        ]
+    );
+    assert_eq!(of("fallthrough"), ["fallthrough /missing"]);
+    assert_eq!(of("render_error"), ["render_error pages/broken.html"]);
+}
+
+#[actix_web::test]
+async fn override_handlers_build_the_response() {
+    let page = TeraPage::static_only("pages")
+        .noindex(&["/"])
+        .override_handler("pages/report.csv", |req, body| {
+            HttpResponse::Accepted()
+                .insert_header(("x-report", req.path().to_string()))
+                .body(body.to_uppercase())
+        });
+    let app = app(
+        page,
+        tera(&[("pages/report.csv", "a,b"), ("pages/about.html", "about")]),
+    )
+    .await;
+
+    let report = get(&app, "/report.csv").await;
+    assert_eq!(report.status, StatusCode::ACCEPTED);
+    assert_eq!(report.header("x-report"), Some("/report.csv"));
+    assert_eq!(report.header("x-robots-tag"), None);
+    assert_eq!(report.text(), "A,B");
+
+    let about = get(&app, "/about").await;
+    assert_eq!(about.status, StatusCode::OK);
+    assert_eq!(about.header("x-robots-tag"), Some("noindex, nofollow"));
+}
+
+#[actix_web::test]
+async fn htmx_requests_get_fragments_and_headers() {
+    let page = TeraPage::new("pages", |_| async {
+        let mut context = Context::new();
+        context.insert("events", "cart-updated");
+        context
+    })
+    .htmx(HtmxConfig {
+        trigger_key: Some("events".to_string()),
+        ..HtmxConfig::default()
+    });
+    let app = app(
+        page,
+        tera(&[
+            ("pages/cart.html", "<html>full cart</html>"),
+            ("pages/cart.fragment.html", "cart rows"),
+        ]),
+    )
+    .await;
+
+    let htmx = TestRequest::get()
+        .uri("/cart?page=2")
+        .insert_header(("hx-request", "true"));
+    let page = call(&app, htmx).await;
+    assert_eq!(page.text(), "cart rows");
+    assert_eq!(page.header("hx-push-url"), Some("/cart?page=2"));
+    assert_eq!(page.header("hx-trigger"), Some("cart-updated"));
+    assert!(page.header_values("vary").contains(&"hx-request"));
+
+    let page = get(&app, "/cart").await;
+    assert_eq!(page.text(), "<html>full cart</html>");
+    assert_eq!(page.header("hx-push-url"), None);
+    assert!(page.header_values("vary").contains(&"hx-request"));
+}
+
+#[actix_web::test]
+async fn runs_of_slashes_are_collapsed() {
+    let templates = [("pages/blog/post.html", "post")];
+
+    let collapsing = app(TeraPage::static_only("pages"), tera(&templates)).await;
+    assert_eq!(get(&collapsing, "/blog//post").await.text(), "post");
+
+    let literal = app(
+        TeraPage::static_only("pages").collapse_slashes(false),
+        tera(&templates),
+    )
+    .await;
+    assert_eq!(get(&literal, "/blog//post").await.text(), INNER);
+
+    let redirecting = app(
+        TeraPage::static_only("pages").redirect_collapsed_slashes(true),
+        tera(&templates),
+    )
+    .await;
+    let page = get(&redirecting, "//blog///post").await;
+    assert_eq!(page.status, StatusCode::PERMANENT_REDIRECT);
+    assert_eq!(page.header("location"), Some("/blog/post"));
+    assert_eq!(get(&redirecting, "/blog/post").await.text(), "post");
+}
+
+#[actix_web::test]
+async fn slow_renders_time_out() {
+    let hurried = app(
+        TeraPage::static_only("pages").render_timeout(Duration::from_millis(1)),
+        tera(&[(
+            "pages/slow.html",
+            "{% for i in range(end=2000000) %}{% endfor %}slow",
+        )]),
+    )
+    .await;
+
+    let started = Instant::now();
+    let page = get(&hurried, "/slow").await;
+    assert_eq!(page.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert!(started.elapsed() < Duration::from_secs(1));
+
+    let patient = app(
+        TeraPage::static_only("pages").render_timeout(Duration::from_secs(5)),
+        tera(&[("pages/fast.html", "fast")]),
+    )
+    .await;
+    assert_eq!(get(&patient, "/fast").await.text(), "fast");
+}
+
+#[actix_web::test]
+async fn blocking_renders_match_inline_renders() {
+    let templates = [
+        (
+            "base.html",
+            "<title>{% block title %}{% endblock %}</title>",
+        ),
+        (
+            "pages/about.html",
+            "{% extends 'base.html' %}{% block title %}{{ name }}{% endblock %}",
+        ),
+    ];
+    let page = || {
+        TeraPage::new("pages", |_| async {
+            let mut context = Context::new();
+            context.insert("name", "About <us>");
+            context
+        })
+    };
+
+    let inline = app(page(), tera(&templates)).await;
+    let blocking = app(page().render_on_blocking(true), tera(&templates)).await;
+    let page = get(&blocking, "/about").await;
+    assert_eq!(page.text(), "<title>About &lt;us&gt;</title>");
+    assert_eq!(page.body, get(&inline, "/about").await.body);
+    assert_eq!(get(&blocking, "/missing").await.text(), INNER);
+}
+
+#[actix_web::test]
+async fn describe_summarizes_the_configuration() {
+    let page = TeraPage::static_only("pages")
+        .max_depth(4)
+        .render_timeout(Duration::from_secs(2))
+        .fail_fast(true)
+        .max_concurrent_renders(8);
+
+    let summary = page.describe();
+    assert_eq!(summary.prefix, "pages");
+    assert_eq!(summary.extension, "html");
+    assert_eq!(summary.index_name, "index");
+    assert_eq!(summary.max_depth, Some(4));
+    assert_eq!(summary.render_timeout, Some(Duration::from_secs(2)));
+    assert!(summary
+        .content_types
+        .contains(&("json".to_string(), "application/json".to_string())));
+    assert!(summary.options.contains(&"fail_fast"));
+    assert!(summary.options.contains(&"max_concurrent_renders"));
+    assert!(!summary.options.contains(&"principal"));
+    assert_eq!(
+        summary.features.contains(&"static-cache"),
+        cfg!(feature = "static-cache")
+    );
+
+    let app = app(page, tera(&[("pages/about.html", "about")])).await;
+    assert_eq!(get(&app, "/about").await.text(), "about");
+}
+
+#[cfg(feature = "csp")]
+#[actix_web::test]
+async fn inline_blocks_are_allowed_by_hash() {
+    let style = "body { color: teal; }";
+    let script = "console.log('hi');";
+    let html = format!(
+        "<style>{}</style><script src=\"/app.js\"></script><script>{}</script>",
+        style, script
+    );
+    let app = app(
+        TeraPage::static_only("pages").csp_hashes(true),
+        tera(&[("pages/index.html", &html)]),
+    )
+    .await;
+
+    let hash = |content: &str| STANDARD.encode(Sha256::digest(content));
+    let page = get(&app, "/").await;
+    assert_eq!(page.text(), html);
+    assert_eq!(
+        page.header("content-security-policy"),
+        Some(
+            format!(
+                "script-src 'self' 'sha256-{}'; style-src 'self' 'sha256-{}'",
+                hash(script),
+                hash(style)
+            )
+            .as_str()
+        )
+    );
+}
+
+#[actix_web::test]
+async fn nojs_cookies_select_the_static_variant() {
+    let app = app(
+        TeraPage::static_only("pages").nojs_variant("nojs", "static"),
+        tera(&[
+            ("pages/search.html", "<script>search()</script>"),
+            ("pages/search.static.html", "<form action=\"/find\"></form>"),
+            ("pages/about.html", "about"),
+        ]),
+    )
+    .await;
+    let without_js = |uri| {
+        TestRequest::get()
+            .uri(uri)
+            .cookie(actix_web::cookie::Cookie::new("nojs", "1"))
+    };
+
+    let page = call(&app, without_js("/search")).await;
+    assert_eq!(page.text(), "<form action=\"/find\"></form>");
+    assert!(page.header_values("vary").contains(&"cookie"));
+    assert_eq!(call(&app, without_js("/about")).await.text(), "about");
+
+    let page = get(&app, "/search").await;
+    assert_eq!(page.text(), "<script>search()</script>");
+    assert!(page.header_values("vary").contains(&"cookie"));
+}
+
+#[actix_web::test]
+async fn paths_are_injected_unless_already_set() {
+    let page = TeraPage::new("pages", |req: HttpRequest| async move {
+        let mut context = Context::new();
+        if req.path().starts_with("/docs") {
+            context.insert("normalized_path", "/documentation");
+        }
+        context
+    })
+    .inject_path(true);
+    let nav = "{{ path | safe }} {{ normalized_path | safe }}";
+    let app = app(
+        page,
+        tera(&[
+            ("pages/blog/index.html", nav),
+            ("pages/docs/index.html", nav),
+            ("pages/index.html", nav),
+        ]),
+    )
+    .await;
+
+    assert_eq!(get(&app, "/blog//").await.text(), "/blog// /blog");
+    assert_eq!(get(&app, "/").await.text(), "/ /");
+    assert_eq!(get(&app, "/docs/").await.text(), "/docs/ /documentation");
+}